@@ -0,0 +1,374 @@
+use std::collections::VecDeque;
+
+use crate::mpeg2ts;
+use mpeg2ts::packet::Packet;
+
+// PCR is to be sent at least every 100ms, a larger jump forward than this is taken as a discontinuity
+pub const PCR_JUMP_LIMIT: u64 = mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC;
+
+// PCR is to be sent at least every 100ms, past this the timeline goes on by PTS until PCR comes back or is given up on
+pub const PCR_INTERVAL_LIMIT: u64 = mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC / 10;
+// PCR missing for this long of PTS makes the clock follow PTS instead, in auto
+pub const PCR_ABSENCE_LIMIT: u64 = mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC;
+// a backward step of PTS within this is reordering of frames, not a discontinuity
+pub const REORDER_LIMIT: u64 = mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC / 2;
+
+// what the timeline follows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClockSource {
+  // PCR, falling back to PTS when PCR goes missing
+  #[default]
+  Auto,
+  Pcr,
+  // DTS (or PTS) of the video, or the audio when there is no video
+  Pts,
+}
+
+// how the timeline continues over a PCR discontinuity
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Recovery {
+  // as if the stream had no break, advancing by the PCR interval before it
+  #[default]
+  Contiguous,
+  // as contiguous, then moved so the first video frame after the break follows the last one before it
+  Resync,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscontinuityReason {
+  // discontinuity_indicator of the adaptation field
+  Indicator,
+  Backward,
+  // forward by more than PCR_JUMP_LIMIT
+  Jump,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discontinuity {
+  pub pid: u16,
+  pub previous: u64,
+  pub current: u64,
+  pub reason: DiscontinuityReason,
+}
+
+// timeline of a stream in 90kHz from its first PCR, which keeps going over PCR discontinuities
+#[derive(Default)]
+pub struct Clock {
+  recovery: Recovery,
+  source: ClockSource,
+
+  pcr_pid: Option<u16>,
+  video_pid: Option<u16>,
+  audio_pid: Option<u16>,
+
+  // following PTS, the timestamp of the first PES since the last PCR in auto, and the PID followed once it fell back
+  following_pts: bool,
+  pcr_absence: Option<u64>,
+  fallback: Option<u16>,
+
+  first_pcr: Option<u64>,
+  previous_pcr: Option<u64>,
+  // interval of the last two PCR, which bridges a discontinuity, and the number of packets in it
+  interval: u64,
+  interval_packets: u64,
+  elapsed: u64,
+  // packets since the last PCR, and the position of the last packet interpolated by them
+  packets: u64,
+  position: u64,
+
+  // lead of the first video PTS over PCR
+  pts_lead: Option<i64>,
+  // the latest video presentation on the timeline, and the shortest interval of video PTS as the frame duration
+  previous_pts: Option<u64>,
+  presentation: Option<i64>,
+  frame_interval: Option<u64>,
+  resyncing: bool,
+
+  discontinuities: VecDeque<Discontinuity>,
+}
+
+impl Clock {
+  pub fn new(recovery: Recovery, source: ClockSource) -> Self {
+    return Clock { recovery, source, following_pts: source == ClockSource::Pts, ..Default::default() };
+  }
+
+  // only before any packet is pushed
+  pub fn set_options(&mut self, recovery: Recovery, source: ClockSource) {
+    *self = Clock::new(recovery, source);
+  }
+
+  // a PCR PID change is followed from its next PCR, which is checked against the last PCR of the previous PID
+  pub fn set_pcr_pid(&mut self, pcr_pid: u16) {
+    self.pcr_pid = Some(pcr_pid);
+  }
+
+  pub fn set_video_pid(&mut self, video_pid: Option<u16>) {
+    self.video_pid = video_pid;
+  }
+
+  pub fn set_audio_pid(&mut self, audio_pid: Option<u16>) {
+    self.audio_pid = audio_pid;
+  }
+
+  pub fn pcr_pid(&self) -> Option<u16> {
+    return self.pcr_pid;
+  }
+
+  pub fn video_pid(&self) -> Option<u16> {
+    return self.video_pid;
+  }
+
+  pub fn first_pcr(&self) -> Option<u64> {
+    return self.first_pcr;
+  }
+
+  pub fn pts_lead(&self) -> Option<i64> {
+    return self.pts_lead;
+  }
+
+  // position of the last PCR
+  pub fn elapsed(&self) -> u64 {
+    return self.elapsed;
+  }
+
+  // position of the last packet, interpolated from the last PCR by the packet count at the bitrate between the last two PCR
+  pub fn position(&self) -> u64 {
+    return self.position;
+  }
+
+  // timeline position of timestamp 0 on the current PCR, adding a PTS gives its position on the timeline
+  pub fn base(&self) -> Option<i64> {
+    return self.previous_pcr.map(|pcr| self.elapsed as i64 - pcr as i64);
+  }
+
+  pub fn pop_discontinuity(&mut self) -> Option<Discontinuity> {
+    return self.discontinuities.pop_front();
+  }
+
+  // the PID whose PTS the clock started to follow, as PCR went missing
+  pub fn take_fallback(&mut self) -> Option<u16> {
+    return self.fallback.take();
+  }
+
+  pub fn push(&mut self, packet: &Packet) {
+    let pid = mpeg2ts::packet::pid(packet);
+    self.packets += 1;
+    if self.pcr_pid == Some(pid) && !self.following_pts {
+      if let Some(pcr) = mpeg2ts::packet::pcr(packet) {
+        self.update_pcr(pid, pcr, mpeg2ts::packet::discontinuity_indicator(packet));
+        self.pcr_absence = None;
+      }
+    }
+    // PES headers are only looked into at their start, which may continue into the next packet
+    let starts_pes = mpeg2ts::packet::payload_unit_start_indicator(packet) && mpeg2ts::packet::has_payload(packet);
+    if self.video_pid.or(self.audio_pid) == Some(pid) && starts_pes {
+      let pes = mpeg2ts::pes::from_packet(packet);
+      if let Some(timestamp) = mpeg2ts::pes::dts(&pes).or_else(|| mpeg2ts::pes::pts(&pes)) {
+        self.update_timestamp(pid, timestamp);
+      }
+    }
+
+    // never beyond where the next PCR is expected, nor back before a position already given
+    let interpolated = match self.interval_packets {
+      0 => self.elapsed,
+      packets => self.elapsed + self.interval * self.packets.min(packets) / packets,
+    };
+    self.position = self.position.max(interpolated);
+
+    if self.video_pid == Some(pid) && starts_pes {
+      if let Some(pts) = mpeg2ts::pes::pts(&mpeg2ts::pes::from_packet(packet)) {
+        self.update_pts(pts);
+      }
+    }
+  }
+
+  fn update_pcr(&mut self, pid: u16, pcr: u64, indicator: bool) {
+    let Some(previous) = self.previous_pcr else {
+      // the timeline may have gone on by PTS while the first PCR was late, it continues from there
+      self.elapsed = self.position;
+      self.first_pcr = Some((pcr + mpeg2ts::packet::MPEGTS_TIMESTAMP_CLOCKS - self.position % mpeg2ts::packet::MPEGTS_TIMESTAMP_CLOCKS) % mpeg2ts::packet::MPEGTS_TIMESTAMP_CLOCKS);
+      self.previous_pcr = Some(pcr);
+      self.packets = 0;
+      return;
+    };
+
+    let difference = mpeg2ts::packet::timestamp_difference(pcr, previous);
+    let reason = if indicator {
+      Some(DiscontinuityReason::Indicator)
+    } else if difference < 0 {
+      Some(DiscontinuityReason::Backward)
+    } else if difference as u64 > PCR_JUMP_LIMIT {
+      Some(DiscontinuityReason::Jump)
+    } else {
+      None
+    };
+
+    match reason {
+      Some(reason) => {
+        self.discontinuities.push_back(Discontinuity { pid, previous, current: pcr, reason });
+        self.elapsed += self.interval;
+        self.resyncing = self.recovery == Recovery::Resync;
+      },
+      None => {
+        self.elapsed += difference as u64;
+        self.interval = difference as u64;
+        self.interval_packets = self.packets;
+      },
+    }
+    self.packets = 0;
+    self.previous_pcr = Some(pcr);
+  }
+
+  // the timeline is driven by PTS once following it, or checks whether PCR has gone missing
+  fn update_timestamp(&mut self, pid: u16, timestamp: u64) {
+    if self.following_pts {
+      if let Some(previous) = self.previous_pcr {
+        let difference = mpeg2ts::packet::timestamp_difference(timestamp, previous);
+        if difference < 0 && difference > -(REORDER_LIMIT as i64) { return; }
+      }
+      self.update_pcr(pid, timestamp, false);
+      return;
+    }
+    if self.source != ClockSource::Auto { return; }
+
+    let since = *self.pcr_absence.get_or_insert(timestamp);
+    let absence = mpeg2ts::packet::timestamp_difference(timestamp, since);
+    // while it is not known yet whether PCR is gone, the timeline goes on by PTS rather than stopping and catching up at once
+    if absence > PCR_INTERVAL_LIMIT as i64 {
+      self.position = self.position.max(self.elapsed + absence.min(PCR_ABSENCE_LIMIT as i64) as u64);
+    }
+    if absence <= PCR_ABSENCE_LIMIT as i64 { return; }
+
+    // the time without PCR is taken from PTS, as well as the time from now on
+    self.following_pts = true;
+    self.fallback = Some(pid);
+    if self.previous_pcr.is_none() {
+      self.first_pcr = Some(since);
+    }
+    self.elapsed += absence as u64;
+    self.previous_pcr = Some(timestamp);
+    self.packets = 0;
+  }
+
+  fn update_pts(&mut self, pts: u64) {
+    let Some(pcr) = self.previous_pcr else { return; };
+    let lead = mpeg2ts::packet::timestamp_difference(pts, pcr);
+    self.pts_lead.get_or_insert(lead);
+
+    let mut presentation = self.elapsed as i64 + lead;
+    if std::mem::take(&mut self.resyncing) {
+      if let (Some(previous), Some(frame_interval)) = (self.presentation, self.frame_interval) {
+        // the timeline only goes forward, metadata up to it may have been output already
+        let adjustment = (previous + frame_interval as i64 - presentation).max(0);
+        self.elapsed += adjustment as u64;
+        presentation += adjustment;
+      }
+    } else if let Some(previous_pts) = self.previous_pts {
+      let difference = mpeg2ts::packet::timestamp_difference(pts, previous_pts);
+      if difference > 0 && (difference as u64) < PCR_JUMP_LIMIT {
+        self.frame_interval = Some(self.frame_interval.map_or(difference as u64, |interval| interval.min(difference as u64)));
+      }
+    }
+    self.previous_pts = Some(pts);
+    self.presentation = Some(self.presentation.map_or(presentation, |previous| previous.max(presentation)));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn pcr_packet(pid: u16, pcr: u64, discontinuity: bool) -> Packet {
+    let mut packet = [0xFFu8; mpeg2ts::packet::PACKET_SIZE];
+    packet[0..4].copy_from_slice(&[0x47, (pid >> 8) as u8, pid as u8, 0x20]);
+    packet[4] = 183;
+    packet[5] = 0x10 | if discontinuity { 0x80 } else { 0x00 };
+    packet[6..12].copy_from_slice(&[(pcr >> 25) as u8, (pcr >> 17) as u8, (pcr >> 9) as u8, (pcr >> 1) as u8, (((pcr & 1) << 7) as u8) | 0x7E, 0x00]);
+    return packet;
+  }
+
+  fn pes_packet(pid: u16, pts: u64) -> Packet {
+    let mut packet = [0xFFu8; mpeg2ts::packet::PACKET_SIZE];
+    packet[0..4].copy_from_slice(&[0x47, 0x40 | (pid >> 8) as u8, pid as u8, 0x10]);
+    packet[4..13].copy_from_slice(&[0x00, 0x00, 0x01, 0xE0, 0x00, 0x00, 0x80, 0x80, 0x05]);
+    packet[13..18].copy_from_slice(&[0x21 | (((pts >> 30) & 0x07) << 1) as u8, (pts >> 22) as u8, 0x01 | (((pts >> 15) & 0x7F) << 1) as u8, (pts >> 7) as u8, 0x01 | ((pts & 0x7F) << 1) as u8]);
+    return packet;
+  }
+
+  #[test]
+  fn split_pes_header_is_skipped() {
+    let mut clock = Clock::new(Recovery::Resync, ClockSource::Auto);
+    clock.set_pcr_pid(0x100);
+    clock.set_video_pid(Some(0x100));
+
+    // payload_unit_start_indicator with only 3 bytes of the PES header, the rest is in the next packet
+    let mut packet = [0xFFu8; mpeg2ts::packet::PACKET_SIZE];
+    packet[0..6].copy_from_slice(&[0x47, 0x41, 0x00, 0x30, 180, 0x00]);
+    packet[185..188].copy_from_slice(&[0x00, 0x00, 0x01]);
+    clock.push(&pcr_packet(0x100, 0, false));
+    clock.push(&packet);
+    // adaptation field only, payload_unit_start_indicator without payload
+    packet[3] = 0x20;
+    packet[4] = 183;
+    clock.push(&packet);
+
+    assert_eq!(clock.pts_lead(), None);
+    clock.push(&pes_packet(0x100, 9000));
+    assert_eq!(clock.pts_lead(), Some(9000));
+  }
+
+  #[test]
+  fn fallback_goes_on_without_a_burst() {
+    let mut clock = Clock::new(Recovery::Contiguous, ClockSource::Auto);
+    clock.set_pcr_pid(0x100);
+    clock.set_video_pid(Some(0x100));
+
+    // PTS every 100ms, no PCR at all
+    for frame in 0..=15 {
+      clock.push(&pes_packet(0x100, 100000 + frame * 9000));
+      assert_eq!(clock.position(), if frame <= 1 { 0 } else { frame * 9000 });
+      assert_eq!(clock.take_fallback(), if frame == 11 { Some(0x100) } else { None });
+    }
+    assert_eq!(clock.first_pcr(), Some(100000));
+  }
+
+  #[test]
+  fn late_first_pcr_continues_the_timeline() {
+    let mut clock = Clock::new(Recovery::Contiguous, ClockSource::Auto);
+    clock.set_pcr_pid(0x100);
+    clock.set_video_pid(Some(0x100));
+
+    for frame in 0..=3 {
+      clock.push(&pes_packet(0x100, 100000 + frame * 9000));
+    }
+    assert_eq!(clock.position(), 27000);
+
+    clock.push(&pcr_packet(0x100, 120000, false));
+    assert_eq!(clock.position(), 27000);
+    assert_eq!(clock.first_pcr(), Some(93000));
+    clock.push(&pcr_packet(0x100, 129000, false));
+    assert_eq!(clock.position(), 36000);
+  }
+
+  #[test]
+  fn discontinuities_are_bridged() {
+    let mut clock = Clock::new(Recovery::Contiguous, ClockSource::Pcr);
+    clock.set_pcr_pid(0x100);
+
+    let wrap = mpeg2ts::packet::MPEGTS_TIMESTAMP_CLOCKS;
+    let jump = 4500 + 2 * mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC + 4500;
+    let pcrs = [(0, false), (9000, false), (500000, true), (509000, false), (wrap - 4500, false), (4500, false), (jump, false), (jump + 9000, false)];
+    for (index, (pcr, indicator)) in pcrs.into_iter().enumerate() {
+      clock.push(&pcr_packet(0x100, pcr, indicator));
+      // the interval before a discontinuity goes on over it, and wraparound is not one
+      assert_eq!(clock.elapsed(), index as u64 * 9000);
+    }
+
+    let reasons = std::iter::from_fn(|| clock.pop_discontinuity()).map(|discontinuity| (discontinuity.previous, discontinuity.current, discontinuity.reason)).collect::<Vec<_>>();
+    assert_eq!(reasons, vec![
+      (9000, 500000, DiscontinuityReason::Indicator),
+      (509000, wrap - 4500, DiscontinuityReason::Backward),
+      (4500, jump, DiscontinuityReason::Jump),
+    ]);
+  }
+}
@@ -0,0 +1,253 @@
+use crate::mpeg2ts;
+use crate::error::{Error, Result};
+
+// kept ranges of the metadata timeline (elapsed from its first PCR, in 90kHz), in the order they are concatenated
+#[derive(Debug, Clone)]
+pub struct EditList {
+  ranges: Vec<(u64, u64)>,
+}
+
+enum Json {
+  Number(f64),
+  String(String),
+  Array(Vec<Json>),
+  Object(Vec<(String, Json)>),
+  Other,
+}
+
+struct JsonParser<'a> {
+  text: &'a [u8],
+  offset: usize,
+}
+
+impl<'a> JsonParser<'a> {
+  fn skip_whitespace(&mut self) {
+    while self.offset < self.text.len() && self.text[self.offset].is_ascii_whitespace() {
+      self.offset += 1;
+    }
+  }
+
+  fn expect(&mut self, byte: u8) -> Option<()> {
+    self.skip_whitespace();
+    if self.text.get(self.offset) != Some(&byte) { return None; }
+    self.offset += 1;
+    return Some(());
+  }
+
+  fn string(&mut self) -> Option<String> {
+    self.expect(b'"')?;
+    let begin = self.offset;
+    while self.offset < self.text.len() && self.text[self.offset] != b'"' {
+      self.offset += if self.text[self.offset] == b'\\' { 2 } else { 1 };
+    }
+    let end = self.offset;
+    self.expect(b'"')?;
+    return String::from_utf8(self.text[begin..end].to_vec()).ok();
+  }
+
+  fn value(&mut self) -> Option<Json> {
+    self.skip_whitespace();
+    match self.text.get(self.offset)? {
+      b'[' => {
+        self.offset += 1;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.text.get(self.offset) == Some(&b']') {
+          self.offset += 1;
+          return Some(Json::Array(values));
+        }
+        loop {
+          values.push(self.value()?);
+          self.skip_whitespace();
+          match self.text.get(self.offset)? {
+            b',' => { self.offset += 1; }
+            b']' => { self.offset += 1; return Some(Json::Array(values)); }
+            _ => { return None; }
+          }
+        }
+      },
+      b'{' => {
+        self.offset += 1;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.text.get(self.offset) == Some(&b'}') {
+          self.offset += 1;
+          return Some(Json::Object(members));
+        }
+        loop {
+          let key = self.string()?;
+          self.expect(b':')?;
+          members.push((key, self.value()?));
+          self.skip_whitespace();
+          match self.text.get(self.offset)? {
+            b',' => { self.offset += 1; }
+            b'}' => { self.offset += 1; return Some(Json::Object(members)); }
+            _ => { return None; }
+          }
+        }
+      },
+      b'"' => {
+        return self.string().map(Json::String);
+      },
+      _ => {
+        let begin = self.offset;
+        while self.offset < self.text.len() && !matches!(self.text[self.offset], b',' | b']' | b'}') && !self.text[self.offset].is_ascii_whitespace() {
+          self.offset += 1;
+        }
+        let token = std::str::from_utf8(&self.text[begin..self.offset]).ok()?;
+        return Some(token.parse::<f64>().map(Json::Number).unwrap_or(Json::Other));
+      }
+    }
+  }
+}
+
+// seconds ("123.4") or "HH:MM:SS.sss" / "MM:SS.sss"
+fn parse_time(text: &str) -> Option<u64> {
+  let mut seconds = 0f64;
+  for field in text.trim().split(':') {
+    let value = field.parse::<f64>().ok()?;
+    if !value.is_finite() || value < 0.0 { return None; }
+    seconds = seconds * 60.0 + value;
+  }
+  return Some((seconds * mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC as f64).round() as u64);
+}
+
+fn json_time(value: &Json) -> Option<u64> {
+  return match value {
+    Json::Number(seconds) if seconds.is_finite() && *seconds >= 0.0 => Some((seconds * mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC as f64).round() as u64),
+    Json::String(text) => parse_time(text),
+    _ => None,
+  };
+}
+
+fn json_range(value: &Json) -> Option<(u64, u64)> {
+  return match value {
+    Json::Array(pair) if pair.len() == 2 => Some((json_time(&pair[0])?, json_time(&pair[1])?)),
+    Json::Object(members) => {
+      let start = members.iter().find(|(key, _)| key == "start")?;
+      let end = members.iter().find(|(key, _)| key == "end")?;
+      Some((json_time(&start.1)?, json_time(&end.1)?))
+    },
+    _ => None,
+  };
+}
+
+impl EditList {
+  // JSON array of [start, end] pairs or {"start", "end"} objects, or text with one "start end" pair per line
+  pub fn parse(text: &str) -> Result<Self> {
+    let mut ranges = Vec::new();
+
+    if text.trim_start().starts_with('[') {
+      let mut parser = JsonParser { text: text.as_bytes(), offset: 0 };
+      let value = parser.value();
+      parser.skip_whitespace();
+      let (Some(Json::Array(values)), true) = (value, parser.offset == parser.text.len()) else {
+        return Err(Error::InvalidEditList("malformed json edit list".to_string()));
+      };
+      for value in values {
+        let Some(range) = json_range(&value) else {
+          return Err(Error::InvalidEditList("edit list entry must be [start, end] or {\"start\": start, \"end\": end}".to_string()));
+        };
+        ranges.push(range);
+      }
+    } else {
+      for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() { continue; }
+
+        let fields = line.split(|c: char| c.is_whitespace() || c == ',' || c == '-').filter(|field| !field.is_empty()).collect::<Vec<_>>();
+        let (Some(start), Some(end), 2) = (fields.first().and_then(|field| parse_time(field)), fields.get(1).and_then(|field| parse_time(field)), fields.len()) else {
+          return Err(Error::InvalidEditList(format!("malformed edit list at line {}", number + 1)));
+        };
+        ranges.push((start, end));
+      }
+    }
+
+    for (start, end) in &ranges {
+      if start >= end {
+        return Err(Error::InvalidEditList("edit list range must end after its start".to_string()));
+      }
+    }
+    for window in ranges.windows(2) {
+      if window[0].1 > window[1].0 {
+        return Err(Error::InvalidEditList("edit list ranges must be in order and must not overlap".to_string()));
+      }
+    }
+
+    return Ok(EditList { ranges });
+  }
+
+  // shift from the metadata timeline to the output timeline, None when `elapsed` has been cut
+  pub fn shift(&self, elapsed: u64) -> Option<i64> {
+    let mut output = 0u64;
+    for (start, end) in &self.ranges {
+      if *start <= elapsed && elapsed < *end {
+        return Some(output as i64 - *start as i64);
+      }
+      output += end - start;
+    }
+    return None;
+  }
+
+  // position of `elapsed` on the output timeline, a position which has been cut is where the next kept range starts
+  pub fn position(&self, elapsed: u64) -> u64 {
+    let mut output = 0u64;
+    for (start, end) in &self.ranges {
+      if elapsed < *start { return output; }
+      if elapsed < *end { return output + (elapsed - start); }
+      output += end - start;
+    }
+    return output;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SECOND: u64 = mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC;
+
+  #[test]
+  fn text_and_json() {
+    let text = EditList::parse("0 10\n# commercials\n00:20 - 0:30.5 # second part\n").unwrap();
+    let json = EditList::parse("[[0, 10], {\"start\": \"00:20\", \"end\": 30.5}]").unwrap();
+    assert_eq!(text.ranges, vec![(0, 10 * SECOND), (20 * SECOND, 30 * SECOND + SECOND / 2)]);
+    assert_eq!(json.ranges, text.ranges);
+
+    assert_eq!(text.shift(5 * SECOND), Some(0));
+    assert_eq!(text.shift(15 * SECOND), None);
+    assert_eq!(text.shift(25 * SECOND), Some(-10 * SECOND as i64));
+    assert_eq!(text.position(15 * SECOND), 10 * SECOND);
+    assert_eq!(text.position(40 * SECOND), 20 * SECOND + SECOND / 2);
+  }
+
+  #[test]
+  fn malformed_json() {
+    for text in ["[[0, 10]", "[[0, 10]] [20, 30]", "[[0, 10],]", "[[0]]", "[{\"start\": 0}]", "[[0, \"1:x\"]]", "[[-1, 10]]", "[[0, inf]]", "[[0, NaN]]"] {
+      assert!(EditList::parse(text).is_err(), "{}", text);
+    }
+  }
+
+  #[test]
+  fn ranges_in_order_without_overlap() {
+    assert!(EditList::parse("0 10\n5 20").is_err());
+    assert!(EditList::parse("20 30\n0 10").is_err());
+    assert!(EditList::parse("[[10, 10]]").is_err());
+    assert!(EditList::parse("30 20").is_err());
+    assert!(EditList::parse("0 10 20").is_err());
+    // adjacent ranges are fine
+    assert!(EditList::parse("0 10\n10 20").is_ok());
+  }
+
+  #[test]
+  fn beyond_timestamp_wraparound() {
+    // the timeline goes on past 2^33 (about 26.5 hours) where PCR and PTS wrap around
+    let edit_list = EditList::parse("95400 95500\n95600 95700").unwrap();
+    let elapsed = 95650 * SECOND;
+    assert!(elapsed > mpeg2ts::packet::MPEGTS_TIMESTAMP_CLOCKS);
+
+    assert_eq!(edit_list.shift(elapsed), Some(100 * SECOND as i64 - 95600 * SECOND as i64));
+    assert_eq!(edit_list.position(elapsed), 150 * SECOND);
+    assert_eq!(edit_list.shift(95550 * SECOND), None);
+  }
+}
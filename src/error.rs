@@ -0,0 +1,50 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+  InvalidOption(String),
+  InvalidEditList(String),
+  Open { target: &'static str, error: std::io::Error },
+  Read(std::io::Error),
+  Write(std::io::Error),
+  // bytes left at the end of a file which started a packet but could not complete it
+  TruncatedPacket { offset: u64, length: usize },
+  MissingPat,
+  MissingPmt,
+  // the requested program is not in the PAT of `target`
+  MissingProgram { target: &'static str, program_number: u16, available: Vec<u16> },
+  MalformedSection { pid: u16 },
+  Crc { pid: u16 },
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    return match self {
+      Error::InvalidOption(message) => write!(f, "{}", message),
+      Error::InvalidEditList(message) => write!(f, "{}", message),
+      Error::Open { target, error } => write!(f, "failed to open {}: {}", target, error),
+      Error::Read(error) => write!(f, "failed to read ts file: {}", error),
+      Error::Write(error) => write!(f, "failed to write output ts file: {}", error),
+      Error::TruncatedPacket { offset, length } => write!(f, "truncated packet at offset {} ({} bytes)", offset, length),
+      Error::MissingPat => write!(f, "PAT not found"),
+      Error::MissingPmt => write!(f, "PMT not found"),
+      Error::MissingProgram { target, program_number, available } => {
+        let available = available.iter().map(|program_number| format!("{} (0x{:04X})", program_number, program_number)).collect::<Vec<_>>();
+        write!(f, "program {} (0x{:04X}) not found in {}, available: {}", program_number, program_number, target, available.join(", "))
+      },
+      Error::MalformedSection { pid } => write!(f, "malformed section on PID 0x{:04X}", pid),
+      Error::Crc { pid } => write!(f, "CRC error in section on PID 0x{:04X}", pid),
+    };
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    return match self {
+      Error::Open { error, .. } | Error::Read(error) | Error::Write(error) => Some(error),
+      _ => None,
+    };
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,12 @@
+#![allow(clippy::needless_return, clippy::identity_op, clippy::erasing_op)]
+
+pub mod error;
+pub mod mpeg2ts;
+pub mod edit_list;
+pub mod selection;
+pub mod pid_map;
+pub mod si;
+pub mod clock;
+pub mod metadata;
+pub mod retimer;
+pub mod transplant;
@@ -1,329 +1,356 @@
+#![allow(clippy::needless_return, clippy::identity_op, clippy::erasing_op)]
+
 use std::fs::File;
-use std::io::{BufWriter, Write, Read, stdin, stdout};
-use std::collections::{HashSet, VecDeque, HashMap};
+use std::io::{Write, Read, stdin, stdout};
 use std::env;
 use std::process::exit;
 
 extern crate getopts;
 use getopts::Options;
 
-mod mpeg2ts;
-use mpeg2ts::packetize::packtize_section;
-use mpeg2ts::reader::SectionReader;
+use tssubscript::mpeg2ts;
+use mpeg2ts::reader::{PacketStatistics, SectionEvent, SyncLoss};
+use mpeg2ts::writer::PacketWriter;
+use tssubscript::metadata::{self, MetadataOptions, MetadataSource};
+use tssubscript::edit_list::EditList;
+use tssubscript::selection::{DescriptorFilter, StreamSelection};
+use tssubscript::pid_map::PidMap;
+use tssubscript::si::SiPolicies;
+use tssubscript::clock::{ClockSource, DiscontinuityReason, Recovery};
+use tssubscript::transplant::{ComponentTags, Event, ExistingStreams, Source, TransplantOptions, Transplanter};
+use tssubscript::error::{Error, Result};
+
+fn report_sync_losses(name: &str, losses: impl Iterator<Item = SyncLoss>) {
+  for SyncLoss { offset, skipped } in losses {
+    eprintln!("{}: sync lost at offset {}, skipped {} bytes", name, offset, skipped);
+  }
+}
 
-fn main() {
-  let args: Vec<String> = env::args().collect();
+// every program reads the metadata file on its own, the first one tells about it and the others only keep up
+fn report_metadata_sync_losses<R: Read>(transplanter: &mut Transplanter<R>) {
+  report_sync_losses("metadata", std::iter::from_fn(|| transplanter.metadata_mut().pop_sync_loss()));
+  for index in 1..transplanter.program_count() {
+    while transplanter.program_metadata_mut(index).pop_sync_loss().is_some() {}
+  }
+}
 
+fn report_statistics(name: &str, statistics: PacketStatistics) {
+  eprintln!("{}: {} packets, {} sync losses, {} bytes skipped", name, statistics.packets, statistics.sync_losses, statistics.skipped_bytes);
+}
+
+fn report_event(event: Event) {
+  match event {
+    Event::Aligned { program_number, position, lead_difference } => {
+      if lead_difference.is_none() {
+        eprintln!("timeline alignment of service {}: no video PTS to compare, aligning by PCR only", program_number);
+      }
+      eprintln!("timeline alignment of service {}: input starts at {:.3}s of metadata (PTS lead difference {}ms)",
+        program_number,
+        position as f64 / mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC as f64,
+        lead_difference.unwrap_or(0) * 1000 / mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC as i64);
+      if position < -(metadata::ALIGNMENT_LIMIT as i64) {
+        eprintln!("timeline alignment of service {}: input starts long before metadata, timestamps may not be kept from the source", program_number);
+      }
+    },
+    Event::PidRemapped { source, requested, pid } => {
+      eprintln!("metadata: PID 0x{:04X} is used by the input, stream 0x{:04X} is moved to PID 0x{:04X}", requested, source, pid);
+    },
+    Event::StreamsChanged { program_number, pids } => {
+      let pids = pids.iter().map(|pid| format!("0x{:04X}", pid)).collect::<Vec<_>>();
+      eprintln!("metadata: streams of service {} changed, now transplanting [{}]", program_number, pids.join(", "));
+    },
+    Event::ExistingStream { program_number, pid, stream_type, component_tag, policy } => {
+      let action = match policy {
+        ExistingStreams::Replace => "replaced by the metadata",
+        ExistingStreams::Keep => "kept, the same metadata stream is not transplanted",
+        ExistingStreams::Both => "kept along with the metadata",
+      };
+      let component_tag = component_tag.map(|tag| format!(", component_tag 0x{:02X}", tag)).unwrap_or_default();
+      eprintln!("input: service {} already has stream_type 0x{:02X} on PID 0x{:04X}{}, {}", program_number, stream_type, pid, component_tag, action);
+    },
+    Event::PcrDiscontinuity { source, discontinuity } => {
+      let name = match source { Source::Input => "input", Source::Metadata => "metadata" };
+      let reason = match discontinuity.reason {
+        DiscontinuityReason::Indicator => "discontinuity_indicator",
+        DiscontinuityReason::Backward => "backward jump",
+        DiscontinuityReason::Jump => "forward jump",
+      };
+      eprintln!("{}: PCR discontinuity on PID 0x{:04X} ({}, {:.3}s -> {:.3}s), timeline continued over it",
+        name, discontinuity.pid, reason,
+        discontinuity.previous as f64 / mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC as f64,
+        discontinuity.current as f64 / mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC as f64);
+    },
+    Event::PtsFallback { source, pcr_pid, pid } => {
+      let name = match source { Source::Input => "input", Source::Metadata => "metadata" };
+      let pcr_pid = pcr_pid.map(|pcr_pid| format!(" on PID 0x{:04X}", pcr_pid)).unwrap_or_default();
+      eprintln!("{}: no usable PCR{}, timing follows PTS of PID 0x{:04X} instead", name, pcr_pid, pid);
+    },
+    Event::Section { source, event } => {
+      let name = match source { Source::Input => "input", Source::Metadata => "metadata" };
+      match event {
+        SectionEvent::CrcError { pid, table_id } => {
+          eprintln!("{}: dropped section with CRC error on PID 0x{:04X} (table_id 0x{:02X})", name, pid, table_id);
+        },
+        SectionEvent::Malformed { pid, table_id } => {
+          eprintln!("{}: dropped malformed section on PID 0x{:04X} (table_id 0x{:02X})", name, pid, table_id);
+        },
+        SectionEvent::NotCurrent { pid, table_id, table_id_extension, version } => {
+          eprintln!("{}: ignored not yet applicable table_id 0x{:02X} (0x{:04X}) version {} on PID 0x{:04X} (current_next_indicator 0)", name, table_id, table_id_extension, version, pid);
+        },
+        SectionEvent::VersionChanged { pid, table_id, table_id_extension, previous, current } => {
+          eprintln!("{}: table_id 0x{:02X} (0x{:04X}) on PID 0x{:04X} changed version {} -> {}", name, table_id, table_id_extension, pid, previous, current);
+        },
+        SectionEvent::ContinuityGap { pid, expected, actual, discarded } => {
+          eprintln!("{}: continuity counter gap on PID 0x{:04X} (expected {}, got {}){}", name, pid, expected, actual, if discarded { ", partial section discarded" } else { "" });
+        },
+      }
+    }
+  }
+}
+
+fn exit_code(error: &Error) -> i32 {
+  return match error {
+    Error::InvalidOption(_) | Error::InvalidEditList(_) => 1,
+    Error::Open { .. } => 2,
+    Error::Read(_) => 3,
+    Error::Write(_) => 4,
+    Error::TruncatedPacket { .. } => 5,
+    Error::MissingPat => 6,
+    Error::MissingPmt => 7,
+    Error::MissingProgram { .. } => 10,
+    Error::MalformedSection { .. } => 8,
+    Error::Crc { .. } => 9,
+  };
+}
+
+// decimal or 0x prefixed hexadecimal
+fn parse_program_number(text: &str, name: &str) -> Result<u16> {
+  let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+    Some(hex) => u16::from_str_radix(hex, 16),
+    None => text.parse::<u16>(),
+  };
+  return parsed.map_err(|_| Error::InvalidOption(format!("{} must be an integer from 0 to 65535", name)));
+}
+
+fn run(args: &[String]) -> Result<()> {
   let mut opts = Options::new();
   opts.optopt("i", "input", "input mpegts file", "INPUT_PATH");
   opts.optopt("m", "metadata", "metadata mpegts file", "META_PATH");
   opts.optopt("o", "output", "output mpegts file", "OUTPUT_PATH");
-
-  let Ok(matches) = opts.parse(&args[1..]) else {
-    eprintln!("failed to parse command line options");
-    exit(1);
+  opts.optflag("", "align", "align timelines by absolute PCR/PTS values, for encodes which kept source timestamps");
+  opts.optopt("", "edit-list", "edit list of kept metadata ranges in seconds, as text lines \"start end\" or json [[start, end], ...]", "EDIT_LIST_PATH");
+  opts.optopt("", "streams", "metadata streams to transplant, comma separated presets (captions, superimpose, data-broadcasting, private-data) or rules type=0x06+tag=0x30-0x37+descriptor=0xFD (default: superimpose,data-broadcasting)", "SELECTION");
+  opts.optopt("", "service-id", "service_id of the service to take the metadata of (default: the first one in the PAT)", "SERVICE_ID");
+  opts.optopt("", "program-number", "program_number of the input program to transplant into (default: the first one in the PAT)", "PROGRAM_NUMBER");
+  opts.optopt("", "programs", "pairs of input program_number and metadata service_id for multi program output, e.g. 1=1024,2=1032", "PAIRS");
+  opts.optopt("", "si", "SI policy per table (nit, sdt, eit, tot, sdtt, bit, cdt, sit) as table=metadata|input|drop|merge, comma separated (default: nit,sdt,eit,tot,bit from metadata, the others from input)", "POLICIES");
+  opts.optopt("", "program-descriptors", "program_info descriptors of the metadata PMT to carry into the output PMT: all, none, keep:TAGS or drop:TAGS, e.g. keep:0xC1,0xDE (default: none)", "FILTER");
+  opts.optopt("", "component-tags", "give input video/audio without stream_identifier_descriptor a component_tag: none, default (video 0x00, audio 0x10 onwards) or source (from the metadata streams) (default: none)", "MODE");
+  opts.optopt("", "existing-streams", "private data streams (0x06/0x0D) already in the input: replace, keep or both (default: replace)", "POLICY");
+  opts.optopt("", "pcr-recovery", "timeline over a PCR discontinuity: contiguous or resync (on video PTS) (default: contiguous)", "MODE");
+  opts.optopt("", "clock", "what timing follows: auto (PCR, or PTS when PCR is missing), pcr or pts (default: auto)", "SOURCE");
+  opts.optopt("", "pid-map", "output PIDs of metadata streams, e.g. 0x130=0x0130,0x138=0x0200 (default: keep the metadata PID unless it collides with the input)", "MAPPING");
+  opts.optflag("", "rewrite-pes", "rewrite PTS/DTS of transplanted caption/superimpose PES onto the output timeline");
+  opts.optopt("", "pes-offset", "additional offset for rewritten PTS/DTS in milliseconds (default: 0)", "MILLISECONDS");
+  opts.optopt("", "output-packet-size", "output packet size, 188 or 192 (default: 188)", "SIZE");
+
+  let Ok(matches) = opts.parse(args) else {
+    return Err(Error::InvalidOption("failed to parse command line options".to_string()));
   };
 
   let input_file_path = matches.opt_str("i");
   let meta_file_path = matches.opt_str("m");
   let output_file_path = matches.opt_str("o");
+  let output_packet_size = match matches.opt_str("output-packet-size").as_deref() {
+    None | Some("188") => mpeg2ts::packet::PACKET_SIZE,
+    Some("192") => mpeg2ts::packet::TIMESTAMPED_PACKET_SIZE,
+    Some(_) => {
+      return Err(Error::InvalidOption("output packet size must be 188 or 192".to_string()));
+    }
+  };
 
-  let si_pids: HashSet<u16> = vec![0x10 /*NIT*/, 0x11 /*SDT*/, 0x12 /*EIT*/, 0x14 /*TOT*/,  0x24 /*BIT*/].into_iter().collect();
-
-  let replace_pmt_offsets = 0;
-  let mut pat_transport_stream_id = None;
-  let mut pmt_program_number = None;
-  let mut replace_pmt_streams: HashMap<u16, (u8, u16, Vec<u8>)> = HashMap::new();
-
-  let mut meta_queue = VecDeque::new();
-  if let Some(meta_file_path) = meta_file_path {
-    let Ok(meta) = File::open(meta_file_path) else {
-      eprintln!("failed to open metadata ts file");
-      exit(1);
-    };
-    let mut reader = mpeg2ts::reader::PacketReader::new(meta);
-
-    let mut pat_reader = SectionReader::new();
-    let mut pmt_reader = SectionReader::new();
-
-    let mut pmt_pid = None;
-    let mut pcr_pid = None;
-
-    let mut previous_pcr = None;
-    let mut elapsed = 0u64;
-
-    while let Ok(packet) = reader.read() {
-      let pid =  mpeg2ts::packet::pid(&packet);
-
-      match pid {
-        0 => {
-          pat_reader.push(&packet);
-          while let Some(pat) = pat_reader.pop() {
-            pmt_pid = None;
-            if pat_transport_stream_id.is_none() {
-              pat_transport_stream_id = Some(mpeg2ts::section::table_id_extension(&pat));
-            }
-
-            let mut begin = mpeg2ts::section::EXTENDED_HEADER_SIZE;
-            while begin < mpeg2ts::section::BASIC_HEADER_SIZE + mpeg2ts::section::section_length(&pat) - mpeg2ts::section::CRC_SIZE {
-              let program_number = ((pat[begin + 0] as u16) << 8) | ((pat[begin + 1] as u16) << 0);
-              let program_map_pid = (((pat[begin + 2] & 0x1F) as u16) << 8) | ((pat[begin + 3] as u16) << 0);
-
-              if program_map_pid == 0x10 {  // NIT
-                begin += 4;
-                continue;
-              }
-
-              if pmt_pid.is_none() {
-                pmt_pid = Some(program_map_pid);
-              }
-              if pmt_program_number.is_none() {
-                pmt_program_number = Some(program_number);
-              }
-
-              begin += 4;
-            }
-          }
-        },
-        _ if pmt_pid == Some(pid) => {
-          pmt_reader.push(&packet);
-          while let Some(pmt) = pmt_reader.pop() {
-            pcr_pid = Some((((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 0] & 0x1F) as u16) << 8) | ((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 1] as u16) << 0));
-
-            let program_info_length = ((((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 2] & 0x0F) as u16) << 8) | ((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 3] as u16) << 0)) as usize;
-            {
-              let mut begin = mpeg2ts::section::EXTENDED_HEADER_SIZE + 4 + program_info_length;
-              while begin < mpeg2ts::section::BASIC_HEADER_SIZE + mpeg2ts::section::section_length(&pmt) - mpeg2ts::section::CRC_SIZE {
-                let stream_type = pmt[begin + 0];
-                let elementary_pid = (((pmt[begin + 1] & 0x1F) as u16) << 8) | ((pmt[begin + 2] as u16) << 0);
-                let es_info_length = (((pmt[begin + 3] & 0x0F) as usize) << 8) | ((pmt[begin + 4] as usize) << 0);
-
-                match stream_type {
-                  0x06 => {
-                    let mut offset = begin + 5;
-                    while offset < begin + 5 + es_info_length {
-                      let tag = pmt[offset + 0];
-                      let length = pmt[offset + 1] as usize;
-
-                      if tag == 0x52 && pmt[offset + 2] == 0x38 {
-                        replace_pmt_streams.insert(elementary_pid, (stream_type, replace_pmt_offsets + elementary_pid, pmt[begin + 5 .. begin + 5 + es_info_length].to_vec()));
-                      }
-
-                      offset += 2 + length;
-                    }
-                  },
-                  0x0D => {
-                    replace_pmt_streams.insert(elementary_pid, (stream_type, replace_pmt_offsets + elementary_pid, pmt[begin + 5 .. begin + 5 + es_info_length].to_vec()));
-                  },
-                  _ => {}
-                }
-
-                begin += 5 + es_info_length;
-              }
-            }
-          }
-        }
-        _ => {}
-      };
-
-      if si_pids.contains(&pid) || replace_pmt_streams.contains_key(&pid) {
-        meta_queue.push_back((elapsed, pid, packet));
-      };
-
-      if pcr_pid == Some(pid) && mpeg2ts::packet::has_pcr(&packet){
-        if let Some(pcr) = mpeg2ts::packet::pcr(&packet) {
-          elapsed += ((pcr - previous_pcr.unwrap_or(pcr)) + mpeg2ts::packet::MPEGTS_TIMESTAMP_CLOCKS) % mpeg2ts::packet::MPEGTS_TIMESTAMP_CLOCKS;
-          previous_pcr = Some(pcr);
-        }
-      };
+  let pes_offset = match matches.opt_str("pes-offset").map(|offset| offset.parse::<i64>()) {
+    None => 0,
+    Some(Ok(offset)) => offset,
+    Some(Err(_)) => {
+      return Err(Error::InvalidOption("pes offset must be an integer in milliseconds".to_string()));
     }
-  } else {
-    eprintln!("Please specify metadata file");
-    exit(1);
   };
-  let Some(pat_transport_stream_id) = pat_transport_stream_id else {
-    eprintln!("failed to detect transport stream id");
-    exit(1);
+  let edit_list = match matches.opt_str("edit-list") {
+    Some(path) => {
+      let text = std::fs::read_to_string(path).map_err(|error| Error::Open { target: "edit list file", error })?;
+      Some(EditList::parse(&text)?)
+    },
+    None => None,
   };
-  let Some(pmt_program_number) = pmt_program_number else {
-    eprintln!("failed to detect program number");
-    exit(1);
+  let selection = match matches.opt_str("streams") {
+    Some(text) => StreamSelection::parse(&text)?,
+    None => StreamSelection::default(),
+  };
+  let service_id = matches.opt_str("service-id").map(|text| parse_program_number(&text, "service id")).transpose()?;
+  let program_number = matches.opt_str("program-number").map(|text| parse_program_number(&text, "program number")).transpose()?;
+  let pairs = match matches.opt_str("programs") {
+    Some(_) if service_id.is_some() || program_number.is_some() => {
+      return Err(Error::InvalidOption("--programs can't be used with --service-id or --program-number".to_string()));
+    },
+    Some(text) => {
+      let mut pairs = Vec::new();
+      for pair in text.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let Some((program_number, service_id)) = pair.split_once('=') else {
+          return Err(Error::InvalidOption(format!("malformed program pair \"{}\"", pair)));
+        };
+        pairs.push((Some(parse_program_number(program_number.trim(), "program number")?), Some(parse_program_number(service_id.trim(), "service id")?)));
+      }
+      pairs
+    },
+    None => vec![(program_number, service_id)],
+  };
+  let pid_map = match matches.opt_str("pid-map") {
+    Some(text) => PidMap::parse(&text)?,
+    None => PidMap::default(),
+  };
+  let si_policies = match matches.opt_str("si") {
+    Some(text) => SiPolicies::parse(&text)?,
+    None => SiPolicies::default(),
+  };
+  let program_descriptors = match matches.opt_str("program-descriptors") {
+    Some(text) => DescriptorFilter::parse(&text)?,
+    None => DescriptorFilter::None,
+  };
+  let component_tags = match matches.opt_str("component-tags").as_deref() {
+    None | Some("none") => ComponentTags::None,
+    Some("default") => ComponentTags::Default,
+    Some("source") => ComponentTags::Source,
+    Some(_) => {
+      return Err(Error::InvalidOption("component tags must be none, default or source".to_string()));
+    }
+  };
+  let existing_streams = match matches.opt_str("existing-streams").as_deref() {
+    None | Some("replace") => ExistingStreams::Replace,
+    Some("keep") => ExistingStreams::Keep,
+    Some("both") => ExistingStreams::Both,
+    Some(_) => {
+      return Err(Error::InvalidOption("existing streams policy must be replace, keep or both".to_string()));
+    }
+  };
+  let pcr_recovery = match matches.opt_str("pcr-recovery").as_deref() {
+    None | Some("contiguous") => Recovery::Contiguous,
+    Some("resync") => Recovery::Resync,
+    Some(_) => {
+      return Err(Error::InvalidOption("pcr recovery must be contiguous or resync".to_string()));
+    }
+  };
+  let clock_source = match matches.opt_str("clock").as_deref() {
+    None | Some("auto") => ClockSource::Auto,
+    Some("pcr") => ClockSource::Pcr,
+    Some("pts") => ClockSource::Pts,
+    Some(_) => {
+      return Err(Error::InvalidOption("clock must be auto, pcr or pts".to_string()));
+    }
+  };
+  let options = TransplantOptions {
+    align: matches.opt_present("align"),
+    rewrite_pes: matches.opt_present("rewrite-pes"),
+    pes_offset,
+    pid_map,
+    si_policies: si_policies.clone(),
+    program_descriptors,
+    component_tags,
+    existing_streams,
+    pcr_recovery,
+    clock_source,
   };
 
-  {
-    let input_file = match input_file_path {
-      Some(path) => File::open(path).map(|f| Box::new(f) as Box<dyn Read>).unwrap_or_else(|_| {
-        eprintln!("failed to open input ts file");
-        exit(1);
-      }),
-      None => Box::new(stdin()) as Box<dyn Read>
-    };
-    let mut reader = mpeg2ts::reader::PacketReader::new(input_file);
-
-    let output_file =  match output_file_path {
-      Some(path) => File::create(path).map(|f| Box::new(f) as Box<dyn Write>).unwrap_or_else(|_| {
-        eprintln!("failed to open output ts file");
-        exit(1);
-      }),
-      None => Box::new(stdout()) as Box<dyn Write>
-    };
-    let mut writer: BufWriter<Box<dyn Write>> = BufWriter::new(output_file);
-
-    let mut pat_reader = SectionReader::new();
-    let mut pmt_reader = SectionReader::new();
-
-    let mut pmt_pid = None;
-    let mut pcr_pid = None;
-
-    let mut pat_cc = 0u8;
-    let mut pmt_cc = 0u8;
-
-    let mut previous_pcr = None;
-    let mut elapsed = 0u64;
-
-    while let Ok(packet) = reader.read() {
-      let pid =  mpeg2ts::packet::pid(&packet);
-
-      match pid {
-        0 => {
-          pat_reader.push(&packet);
-          while let Some(pat) = pat_reader.pop() {
-            pmt_pid = None;
-
-            {
-              let mut begin = mpeg2ts::section::EXTENDED_HEADER_SIZE;
-              while begin < mpeg2ts::section::BASIC_HEADER_SIZE + mpeg2ts::section::section_length(&pat) - mpeg2ts::section::CRC_SIZE {
-                let program_map_pid = (((pat[begin + 2] & 0x1F) as u16) << 8) | ((pat[begin + 3] as u16) << 0);
-
-                if program_map_pid == 0x10 /* NIT */ {
-                  begin += 4;
-                  continue;
-                }
-
-                pmt_pid = Some(program_map_pid);
-                break;
-              }
-            }
-
-            let Some(pmt_pid) = pmt_pid else { break; };
-
-            let mut new_pat: Vec<u8> = Vec::new();
-            new_pat.extend(pat[0..mpeg2ts::section::EXTENDED_HEADER_SIZE].iter());
-            new_pat[3] = ((pat_transport_stream_id & 0xFF00) >> 8) as u8;
-            new_pat[4] = ((pat_transport_stream_id & 0x00FF) >> 0) as u8;
-            new_pat.push(((0 & 0xFF00) >> 8) as u8);
-            new_pat.push(((0 & 0x00FF) >> 0) as u8);
-            new_pat.push(((0x10 & 0x1F00) >> 8) as u8);
-            new_pat.push(((0x10 & 0x00FF) >> 0) as u8);
-            new_pat.push(((pmt_program_number & 0xFF00) >> 8) as u8);
-            new_pat.push(((pmt_program_number & 0x00FF) >> 0) as u8);
-            new_pat.push(((pmt_pid & 0x1F00) >> 8) as u8);
-            new_pat.push(((pmt_pid & 0x00FF) >> 0) as u8);
-
-            // section length
-            new_pat[1] = (new_pat[1] & 0xF0) | (((new_pat.len() + mpeg2ts::section::CRC_SIZE - mpeg2ts::section::BASIC_HEADER_SIZE) & 0x0F00) >> 8) as u8;
-            new_pat[2] = (((new_pat.len() + mpeg2ts::section::CRC_SIZE - mpeg2ts::section::BASIC_HEADER_SIZE) & 0x00FF) >> 0) as u8;
-
-            // crc32
-            let crc32 = mpeg2ts::section::crc32(&new_pat);
-            new_pat.push(((crc32 & 0xFF000000u32) >> 24) as u8);
-            new_pat.push(((crc32 & 0x00FF0000u32) >> 16) as u8);
-            new_pat.push(((crc32 & 0x0000FF00u32) >> 8) as u8);
-            new_pat.push(((crc32 & 0x000000FFu32) >> 0) as u8);
-
-            let packets = packtize_section(&new_pat, pid, pat_cc);
-            pat_cc = (pat_cc + ((packets.len() % 0x0F) as u8)) & 0x0F;
-            for packet in packets {
-              let Ok(_) = writer.write(&packet) else {
-                eprintln!("failed to write output ts file");
-                exit(1);
-              };
-            }
-          }
-        },
-        _ if pmt_pid == Some(pid) => {
-          pmt_reader.push(&packet);
-          while let Some(pmt) = pmt_reader.pop() {
-            pcr_pid = Some((((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 0] & 0x1F) as u16) << 8) | ((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 1] as u16) << 0));
-
-            let mut new_pmt: Vec<u8> = Vec::new();
-
-            let program_info_length = ((((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 2] & 0x0F) as u16) << 8) | ((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 3] as u16) << 0)) as usize;
-            let mut begin = mpeg2ts::section::EXTENDED_HEADER_SIZE + 4 + program_info_length;
-            new_pmt.extend(pmt[0..begin].iter());
-            new_pmt[3] = ((pmt_program_number & 0xFF00) >> 8) as u8;
-            new_pmt[4] = ((pmt_program_number & 0x00FF) >> 0) as u8;
+  let si_pids = si_policies.metadata_pids();
 
-            while begin < mpeg2ts::section::BASIC_HEADER_SIZE + mpeg2ts::section::section_length(&pmt) - mpeg2ts::section::CRC_SIZE {
-              let es_info_length = (((pmt[begin + 3] & 0x0F) as usize) << 8) | ((pmt[begin + 4] as usize) << 0);
-              new_pmt.extend(pmt[begin..begin + 5 + es_info_length].iter());
-              begin += 5 + es_info_length;
-            }
-            let mut append_streams = Vec::from_iter(replace_pmt_streams.values());
-            append_streams.sort_by(|(_, p1, _), (_, p2, _)| { p1.cmp(p2) });
-            for (stream_type, elementary_pid, esinfo) in append_streams {
-              new_pmt.push(*stream_type);
-              new_pmt.push(((elementary_pid & 0x1F00) >> 8) as u8);
-              new_pmt.push(((elementary_pid & 0x00FF) >> 0) as u8);
-              new_pmt.push(((esinfo.len() & 0x0F00) >> 8) as u8);
-              new_pmt.push(((esinfo.len() & 0x00FF) >> 0) as u8);
-              new_pmt.extend(esinfo);
-            }
+  let Some(meta_file_path) = meta_file_path else {
+    return Err(Error::InvalidOption("Please specify metadata file".to_string()));
+  };
+  // each service reads the metadata file on its own, so it is scheduled by its own PCR
+  let mut sources = Vec::new();
+  for (program_number, service_id) in pairs {
+    let meta = File::open(&meta_file_path).map_err(|error| Error::Open { target: "metadata ts file", error })?;
+    let meta = MetadataSource::new(meta, MetadataOptions {
+      service_id,
+      edit_list: edit_list.clone(),
+      selection: selection.clone(),
+      si_pids: si_pids.clone(),
+    });
+    sources.push((program_number, meta));
+  }
+  let mut transplanter = Transplanter::with_programs(sources, options)?;
 
-            // section length
-            new_pmt[1] = (new_pmt[1] & 0xF0) | (((new_pmt.len() + mpeg2ts::section::CRC_SIZE - mpeg2ts::section::BASIC_HEADER_SIZE) & 0x0F00) >> 8) as u8;
-            new_pmt[2] = (((new_pmt.len() + mpeg2ts::section::CRC_SIZE - mpeg2ts::section::BASIC_HEADER_SIZE) & 0x00FF) >> 0) as u8;
+  let input_file = match input_file_path {
+    Some(path) => Box::new(File::open(path).map_err(|error| Error::Open { target: "input ts file", error })?) as Box<dyn Read>,
+    None => Box::new(stdin()) as Box<dyn Read>
+  };
+  let mut reader = mpeg2ts::reader::PacketReader::new(input_file);
 
-            // crc32
-            let crc32 = mpeg2ts::section::crc32(&new_pmt);
-            new_pmt.push(((crc32 & 0xFF000000u32) >> 24) as u8);
-            new_pmt.push(((crc32 & 0x00FF0000u32) >> 16) as u8);
-            new_pmt.push(((crc32 & 0x0000FF00u32) >> 8) as u8);
-            new_pmt.push(((crc32 & 0x000000FFu32) >> 0) as u8);
+  let output_file = match output_file_path {
+    Some(path) => Box::new(File::create(path).map_err(|error| Error::Open { target: "output ts file", error })?) as Box<dyn Write>,
+    None => Box::new(stdout()) as Box<dyn Write>
+  };
+  let mut writer = PacketWriter::new(output_file, output_packet_size);
+
+  // a truncated last packet still lets everything before it be written out
+  let mut truncated = None;
+  loop {
+    let packet = match reader.read() {
+      Ok(Some(packet)) => packet,
+      Ok(None) => break,
+      Err(error @ Error::TruncatedPacket { .. }) => {
+        truncated = Some(error);
+        break;
+      },
+      Err(error) => return Err(error),
+    };
+    report_sync_losses("input", std::iter::from_fn(|| reader.pop_sync_loss()));
+
+    let packets = transplanter.push(&packet)?;
+    // input packets keep their arrival timestamps, the others are spread between them.
+    // Without them in the input, everything is spread along the input timeline
+    for (index, packet) in packets.iter().enumerate() {
+      match (reader.arrival_clock(), transplanter.passed_through()) {
+        (Some(arrival_clock), Some(passed_through)) if passed_through == index => writer.write_arrived(packet, arrival_clock),
+        _ => writer.write(packet),
+      }.map_err(Error::Write)?;
+    }
+    match (reader.arrival_clock(), transplanter.passed_through()) {
+      (Some(_), Some(_)) => Ok(()),
+      (Some(arrival_clock), None) => writer.arrive(arrival_clock),
+      (None, _) => writer.set_arrival_clock(transplanter.program_clock(0).position() * mpeg2ts::packet::MPEGTS_SYSTEM_CLOCK_PER_TIMESTAMP),
+    }.map_err(Error::Write)?;
+
+    while let Some(event) = transplanter.pop_event() {
+      report_event(event);
+    }
+    report_metadata_sync_losses(&mut transplanter);
+  }
 
-            let packets = packtize_section(&new_pmt, pid, pmt_cc);
-            pmt_cc = (pmt_cc + ((packets.len() % 0x0F) as u8)) & 0x0F;
-            for packet in packets {
-              let Ok(_) = writer.write(&packet) else {
-                eprintln!("failed to write output ts file");
-                exit(1);
-              };
-            }
-          }
-        }
-        _ if si_pids.contains(&pid) || replace_pmt_streams.contains_key(&pid) => {},
-        _ => {
-          let Ok(_) = writer.write(&packet) else {
-            eprintln!("failed to write output ts file");
-            exit(1);
-          };
-        }
-      };
+  writer.flush().map_err(Error::Write)?;
 
-      while !meta_queue.is_empty() {
-        let Some(mut meta) = meta_queue.pop_front() else { break; };
-        if meta.0 >= elapsed {
-          meta_queue.push_front(meta);
-          break;
-        }
+  report_sync_losses("input", std::iter::from_fn(|| reader.pop_sync_loss()));
+  report_metadata_sync_losses(&mut transplanter);
+  if reader.statistics().sync_losses > 0 || transplanter.metadata().statistics().sync_losses > 0 {
+    report_statistics("input", reader.statistics());
+    report_statistics("metadata", transplanter.metadata().statistics());
+  }
 
-        if let Some((_, replaced_pid, _)) = replace_pmt_streams.get(&meta.1){
-          meta.2[1] = (meta.2[1] & 0xE0) | (((replaced_pid & 0x1F00) >> 8) as u8);
-          meta.2[2] = (replaced_pid & 0x00FF) as u8;
-        }
+  transplanter.finish()?;
+  if let Some(error) = transplanter.metadata_mut().take_truncated() {
+    eprintln!("metadata: {}", error);
+  }
+  if let Some(error) = truncated {
+    return Err(error);
+  }
+  return Ok(());
+}
 
-        let Ok(_) = writer.write(&meta.2) else {
-          eprintln!("failed to write output ts file");
-          exit(1);
-        };
-      };
+fn main() {
+  let args: Vec<String> = env::args().collect();
 
-      if pcr_pid == Some(pid) && mpeg2ts::packet::has_pcr(&packet){
-        if let Some(pcr) = mpeg2ts::packet::pcr(&packet) {
-          elapsed += ((pcr - previous_pcr.unwrap_or(pcr)) + mpeg2ts::packet::MPEGTS_TIMESTAMP_CLOCKS) % mpeg2ts::packet::MPEGTS_TIMESTAMP_CLOCKS;
-          previous_pcr = Some(pcr);
-        }
-      };
-    }
+  if let Err(error) = run(&args[1..]) {
+    eprintln!("{}", error);
+    exit(exit_code(&error));
   }
 }
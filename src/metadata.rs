@@ -0,0 +1,400 @@
+use std::io::Read;
+use std::collections::{HashSet, VecDeque, HashMap};
+
+use crate::mpeg2ts;
+use mpeg2ts::packet::Packet;
+use mpeg2ts::reader::{PacketReader, PacketStatistics, SectionEvent, SectionReader, SyncLoss};
+use crate::clock::{Clock, ClockSource, Discontinuity, Recovery};
+use crate::edit_list::EditList;
+use crate::selection::StreamSelection;
+use crate::error::{Error, Result};
+
+pub const ALIGNMENT_LIMIT: u64 = mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC * 10;
+
+pub struct MetadataPacket {
+  // scheduled time on the output timeline, and the shift from the metadata timeline which gave it
+  pub elapsed: u64,
+  pub shift: i64,
+  // timeline position of timestamp 0 when the packet was read, to map its timestamps across PCR discontinuities
+  pub base: i64,
+  pub pid: u16,
+  pub packet: Packet,
+  // packets of the PID were left out since the last one handed out, cut by the edit list or not selected for a while
+  pub skipped: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MetadataOptions {
+  // service to take the metadata of, the first one in the PAT when None
+  pub service_id: Option<u16>,
+  pub edit_list: Option<EditList>,
+  // which streams of the metadata PMT are transplanted
+  pub selection: StreamSelection,
+  // SI PIDs to take from this metadata
+  pub si_pids: HashSet<u16>,
+}
+
+// streams of the PMT, taking effect when the output reaches the time they were read at
+struct PmtChange {
+  elapsed: u64,
+  program_info: Vec<u8>,
+  components: Vec<(u8, Option<u8>)>,
+  streams: HashMap<u16, (u8, Vec<u8>)>,
+}
+
+pub struct MetadataSource<R> {
+  reader: PacketReader<R>,
+  eof: bool,
+  truncated: Option<Error>,
+
+  si_pids: HashSet<u16>,
+
+  pat_reader: SectionReader,
+  pmt_reader: SectionReader,
+
+  pmt_pid: Option<u16>,
+  clock: Clock,
+
+  // selected streams of the PMT last read, which are queued, and the changes which have not yet taken effect
+  selected_streams: HashMap<u16, (u8, Vec<u8>)>,
+  pmt_changes: VecDeque<PmtChange>,
+  pmt_changed: bool,
+
+  pat_transport_stream_id: Option<u16>,
+  // network_PID of the PAT, None for partial TS which has no NIT
+  pat_network_pid: Option<u16>,
+  pmt_program_number: Option<u16>,
+  // the PMT in effect at the output time of the last packet handed out:
+  // program_info descriptors
+  program_info: Vec<u8>,
+  // (stream_type, component_tag) of every stream in order
+  components: Vec<(u8, Option<u8>)>,
+  // (stream_type, ES info) of the selected streams, by their PID in the metadata
+  replace_pmt_streams: HashMap<u16, (u8, Vec<u8>)>,
+
+  service_id: Option<u16>,
+  edit_list: Option<EditList>,
+  selection: StreamSelection,
+  // shift decided at the start of the current section/PES of each PID, None when it is being cut
+  pid_shifts: HashMap<u16, Option<i64>>,
+  // PIDs handed out or cut so far, and whether packets of them were left out since the last one handed out
+  skipped: HashMap<u16, bool>,
+
+  queue: VecDeque<MetadataPacket>,
+}
+
+impl<R: Read> MetadataSource<R> {
+  pub fn new(inner: R, options: MetadataOptions) -> Self {
+    // PAT/PMT are only parsed again when they actually change
+    let mut pat_reader = SectionReader::new();
+    pat_reader.set_changes_only(true);
+    pat_reader.set_well_formed(mpeg2ts::pat::is_well_formed);
+    let mut pmt_reader = SectionReader::new();
+    pmt_reader.set_changes_only(true);
+    pmt_reader.set_well_formed(mpeg2ts::pmt::is_well_formed);
+
+    return MetadataSource {
+      reader: PacketReader::new(inner),
+      eof: false,
+      truncated: None,
+      si_pids: options.si_pids,
+      pat_reader,
+      pmt_reader,
+      pmt_pid: None,
+      clock: Clock::new(Recovery::default(), ClockSource::default()),
+      selected_streams: HashMap::new(),
+      pmt_changes: VecDeque::new(),
+      pmt_changed: false,
+      pat_transport_stream_id: None,
+      pat_network_pid: None,
+      pmt_program_number: None,
+      program_info: Vec::new(),
+      components: Vec::new(),
+      replace_pmt_streams: HashMap::new(),
+      service_id: options.service_id,
+      edit_list: options.edit_list,
+      selection: options.selection,
+      pid_shifts: HashMap::new(),
+      skipped: HashMap::new(),
+      queue: VecDeque::new(),
+    }
+  }
+
+  // reads ahead until the program is identified (PAT and PMT seen), so the PAT/PMT rewrite has what it needs
+  pub fn prime(&mut self) -> Result<()> {
+    while !self.eof && (self.pmt_program_number.is_none() || self.clock.pcr_pid().is_none()) {
+      self.advance()?;
+    }
+
+    // a table which was there but never passed its CRC or structure check is reported as such
+    if self.pat_transport_stream_id.is_none() || self.pmt_program_number.is_none() {
+      if self.pat_reader.statistics().crc_errors > 0 { return Err(Error::Crc { pid: 0 }); }
+      if self.pat_reader.statistics().malformed > 0 { return Err(Error::MalformedSection { pid: 0 }); }
+      return Err(Error::MissingPat);
+    }
+    if self.clock.pcr_pid().is_none() {
+      if let (Some(pid), true) = (self.pmt_pid, self.pmt_reader.statistics().crc_errors > 0) { return Err(Error::Crc { pid }); }
+      if let (Some(pid), true) = (self.pmt_pid, self.pmt_reader.statistics().malformed > 0) { return Err(Error::MalformedSection { pid }); }
+      return Err(Error::MissingPmt);
+    }
+    return Ok(());
+  }
+
+  // reads ahead until the lead of video PTS over PCR is known, giving up after a while for streams without video
+  pub fn prime_alignment(&mut self) -> Result<()> {
+    while !self.eof && self.clock.pts_lead().is_none() && self.clock.elapsed() < ALIGNMENT_LIMIT {
+      self.advance()?;
+    }
+    return Ok(());
+  }
+
+  // the truncated last packet of the metadata file, which only ends the metadata instead of failing the run
+  pub fn take_truncated(&mut self) -> Option<Error> {
+    return self.truncated.take();
+  }
+
+  // SI PIDs to take from this metadata, only before it is primed. The transplanter takes SI from one metadata only
+  pub(crate) fn set_si_pids(&mut self, si_pids: HashSet<u16>) {
+    self.si_pids = si_pids;
+  }
+
+  pub fn si_pids(&self) -> &HashSet<u16> {
+    return &self.si_pids;
+  }
+
+  // what the metadata timeline follows and how it continues over a PCR discontinuity, only before it is primed.
+  // The transplanter makes it the same as the input's
+  pub(crate) fn set_clock_options(&mut self, recovery: Recovery, source: ClockSource) {
+    self.clock.set_options(recovery, source);
+  }
+
+  pub fn pat_transport_stream_id(&self) -> Option<u16> {
+    return self.pat_transport_stream_id;
+  }
+
+  // None for partial TS which has no NIT
+  pub fn pat_network_pid(&self) -> Option<u16> {
+    return self.pat_network_pid;
+  }
+
+  pub fn pmt_program_number(&self) -> Option<u16> {
+    return self.pmt_program_number;
+  }
+
+  // program_info descriptors of the PMT in effect
+  pub fn program_info(&self) -> &[u8] {
+    return &self.program_info;
+  }
+
+  // (stream_type, component_tag) of every stream of the PMT in effect, in order
+  pub fn components(&self) -> &[(u8, Option<u8>)] {
+    return &self.components;
+  }
+
+  // (stream_type, ES info) of the selected streams of the PMT in effect, by their PID in the metadata
+  pub fn replace_pmt_streams(&self) -> &HashMap<u16, (u8, Vec<u8>)> {
+    return &self.replace_pmt_streams;
+  }
+
+  // the PID whose PTS the metadata timeline started to follow, as PCR went missing
+  pub fn take_clock_fallback(&mut self) -> Option<u16> {
+    return self.clock.take_fallback();
+  }
+
+  // the metadata timeline, as far as it has been read ahead
+  pub fn clock(&self) -> &Clock {
+    return &self.clock;
+  }
+
+  pub fn pop_discontinuity(&mut self) -> Option<Discontinuity> {
+    return self.clock.pop_discontinuity();
+  }
+
+  pub fn pop_sync_loss(&mut self) -> Option<SyncLoss> {
+    return self.reader.pop_sync_loss();
+  }
+
+  pub fn statistics(&self) -> PacketStatistics {
+    return self.reader.statistics();
+  }
+
+  pub fn pop_section_event(&mut self) -> Option<SectionEvent> {
+    return self.pat_reader.pop_event().or_else(|| self.pmt_reader.pop_event());
+  }
+
+  // whether the PMT in effect changed since the last call
+  pub fn take_pmt_changed(&mut self) -> bool {
+    return std::mem::take(&mut self.pmt_changed);
+  }
+
+  // hands out the next metadata packet which is scheduled before `elapsed`, reading only as far as needed
+  pub fn pop_before(&mut self, elapsed: u64) -> Result<Option<MetadataPacket>> {
+    while self.queue.is_empty() && !self.eof {
+      self.advance()?;
+    }
+
+    // a PMT change takes effect after the packets scheduled before it
+    while let Some(change) = self.pmt_changes.front() {
+      if change.elapsed >= elapsed || self.queue.front().is_some_and(|front| front.elapsed < change.elapsed) { break; }
+      let Some(change) = self.pmt_changes.pop_front() else { break; };
+      self.program_info = change.program_info;
+      self.components = change.components;
+      self.replace_pmt_streams = change.streams;
+      self.pmt_changed = true;
+    }
+
+    let Some(front) = self.queue.front() else { return Ok(None); };
+    if front.elapsed >= elapsed {
+      return Ok(None);
+    }
+    return Ok(self.queue.pop_front());
+  }
+
+  fn enqueue(&mut self, pid: u16, packet: Packet) {
+    let base = self.clock.base().unwrap_or(0);
+    let Some(edit_list) = &self.edit_list else {
+      let skipped = self.skipped.insert(pid, false).unwrap_or(false);
+      self.queue.push_back(MetadataPacket { elapsed: self.clock.position(), shift: 0, base, pid, packet, skipped });
+      return;
+    };
+
+    // sections and PES are kept or cut as a whole, so nothing is spliced across a cut
+    let shift = if mpeg2ts::packet::payload_unit_start_indicator(&packet) {
+      let shift = edit_list.shift(self.clock.position());
+      self.pid_shifts.insert(pid, shift);
+      shift
+    } else {
+      self.pid_shifts.get(&pid).copied().flatten()
+    };
+    let Some(shift) = shift else {
+      self.skipped.insert(pid, true);
+      return;
+    };
+
+    let elapsed = (self.clock.position() as i64 + shift).max(0) as u64;
+    let skipped = self.skipped.insert(pid, false).unwrap_or(false);
+    self.queue.push_back(MetadataPacket { elapsed, shift, base, pid, packet, skipped });
+  }
+
+  // schedules the PMT just read, the first one takes effect at once
+  fn change_pmt(&mut self, program_info: Vec<u8>, components: Vec<(u8, Option<u8>)>) {
+    if self.pmt_changes.is_empty() && self.replace_pmt_streams == self.selected_streams && self.program_info == program_info && self.components == components { return; }
+
+    let elapsed = match &self.edit_list {
+      Some(edit_list) => edit_list.position(self.clock.position()),
+      None => self.clock.position(),
+    };
+    let streams = self.selected_streams.clone();
+    if self.clock.pcr_pid().is_none() || elapsed == 0 {
+      self.program_info = program_info;
+      self.components = components;
+      self.replace_pmt_streams = streams;
+      return;
+    }
+    self.pmt_changes.push_back(PmtChange { elapsed, program_info, components, streams });
+  }
+
+  fn advance(&mut self) -> Result<()> {
+    let packet = match self.reader.read() {
+      Ok(Some(packet)) => packet,
+      Ok(None) => {
+        self.eof = true;
+        return Ok(());
+      },
+      Err(error @ Error::TruncatedPacket { .. }) => {
+        self.eof = true;
+        self.truncated = Some(error);
+        return Ok(());
+      },
+      Err(error) => { return Err(error); }
+    };
+    let pid = mpeg2ts::packet::pid(&packet);
+
+    match pid {
+      0 => {
+        self.pat_reader.push(&packet);
+        while let Some(pat) = self.pat_reader.pop() {
+          // the reader already dropped and counted broken ones
+          if mpeg2ts::section::validate(&pat, pid).is_err() || !mpeg2ts::pat::is_well_formed(&pat) { continue; }
+
+          self.pmt_pid = None;
+          if self.pat_transport_stream_id.is_none() {
+            self.pat_transport_stream_id = Some(mpeg2ts::section::table_id_extension(&pat));
+          }
+
+          self.pat_network_pid = mpeg2ts::pat::network_pid(&pat);
+          let programs = mpeg2ts::pat::programs(&pat);
+          let program = match self.service_id {
+            Some(service_id) => programs.iter().find(|(program_number, _)| *program_number == service_id),
+            None => programs.first(),
+          };
+          let Some(&(program_number, program_map_pid)) = program else {
+            if let (Some(service_id), None) = (self.service_id, self.pmt_program_number) {
+              return Err(Error::MissingProgram { target: "metadata ts file", program_number: service_id, available: programs.iter().map(|(program_number, _)| *program_number).collect() });
+            }
+            // the service went off air, its streams go with it until it comes back
+            self.selected_streams.clear();
+            self.change_pmt(Vec::new(), Vec::new());
+            continue;
+          };
+
+          self.pmt_pid = Some(program_map_pid);
+          if self.pmt_program_number.is_none() {
+            self.pmt_program_number = Some(program_number);
+          }
+        }
+      },
+      _ if self.pmt_pid == Some(pid) => {
+        self.pmt_reader.push(&packet);
+        while let Some(pmt) = self.pmt_reader.pop() {
+          if mpeg2ts::section::validate(&pmt, pid).is_err() || !mpeg2ts::pmt::is_well_formed(&pmt) { continue; }
+
+          let (mut video_pid, mut audio_pid) = (None, None);
+
+          let program_info_length = ((((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 2] & 0x0F) as u16) << 8) | ((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 3] as u16) << 0)) as usize;
+          let mut begin = mpeg2ts::section::EXTENDED_HEADER_SIZE + 4 + program_info_length;
+          let program_info = pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 4 .. begin].to_vec();
+          let mut components = Vec::new();
+          self.selected_streams.clear();
+          while begin < mpeg2ts::section::BASIC_HEADER_SIZE + mpeg2ts::section::section_length(&pmt) - mpeg2ts::section::CRC_SIZE {
+            let stream_type = pmt[begin + 0];
+            let elementary_pid = (((pmt[begin + 1] & 0x1F) as u16) << 8) | ((pmt[begin + 2] as u16) << 0);
+            let es_info_length = (((pmt[begin + 3] & 0x0F) as usize) << 8) | ((pmt[begin + 4] as usize) << 0);
+
+            if video_pid.is_none() && mpeg2ts::pmt::is_video_stream_type(stream_type) {
+              video_pid = Some(elementary_pid);
+            }
+            if audio_pid.is_none() && mpeg2ts::pmt::is_audio_stream_type(stream_type) {
+              audio_pid = Some(elementary_pid);
+            }
+            components.push((stream_type, mpeg2ts::pmt::component_tag(&pmt[begin + 5 .. begin + 5 + es_info_length])));
+
+            let es_info = &pmt[begin + 5 .. begin + 5 + es_info_length];
+            if self.selection.matches(stream_type, es_info) {
+              self.selected_streams.insert(elementary_pid, (stream_type, es_info.to_vec()));
+            }
+
+            begin += 5 + es_info_length;
+          }
+
+          self.change_pmt(program_info, components);
+          self.clock.set_pcr_pid((((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 0] & 0x1F) as u16) << 8) | ((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 1] as u16) << 0));
+          self.clock.set_video_pid(video_pid);
+          self.clock.set_audio_pid(audio_pid);
+        }
+      }
+      _ => {}
+    };
+
+    // the packet is scheduled at its own position, interpolated between PCR
+    self.clock.push(&packet);
+    if self.si_pids.contains(&pid) || self.selected_streams.contains_key(&pid) {
+      self.enqueue(pid, packet);
+    } else if let Some(skipped) = self.skipped.get_mut(&pid) {
+      // a stream removed from the PMT, or the service off air
+      *skipped = true;
+    };
+
+    return Ok(());
+  }
+}
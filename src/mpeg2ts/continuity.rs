@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+struct Counter {
+  // continuity_counter of the last packet with payload written out
+  output: u8,
+  // the last packet with payload taken from the source, for packets which are forwarded
+  source: Option<super::packet::Packet>,
+}
+
+// continuity counters of the PIDs which are generated or forwarded from another stream, shared by every writer of the output
+#[derive(Default)]
+pub struct ContinuityCounters {
+  counters: HashMap<u16, Counter>,
+}
+
+impl ContinuityCounters {
+  pub fn new() -> Self {
+    return ContinuityCounters { counters: HashMap::new() };
+  }
+
+  // continuity_counter for the first of the packets about to be generated for `pid`
+  pub fn next(&self, pid: u16) -> u8 {
+    return self.counters.get(&pid).map(|counter| (counter.output + 1) & 0x0F).unwrap_or(0);
+  }
+
+  // renumbers a generated packet, continuing from the last packet of its PID
+  pub fn stamp(&mut self, packet: &mut super::packet::Packet) {
+    let pid = super::packet::pid(packet);
+    let cc = self.next(pid);
+    let counter = self.counters.entry(pid).or_insert(Counter { output: cc.wrapping_sub(1) & 0x0F, source: None });
+
+    if super::packet::has_payload(packet) {
+      counter.output = cc;
+    }
+    packet[3] = (packet[3] & 0xF0) | counter.output;
+  }
+
+  // renumbers a packet forwarded from a source stream into `output`. `skipped` tells packets of the PID were left out
+  // since the last one forwarded (cut by the edit list, or not selected for a while), which its continuity_counter may not show.
+  // A duplicate packet of the source is dropped. A gap or a discontinuity of the source gets discontinuity_indicator
+  // on this packet, or on an adaptation field only packet just before it when it has no room
+  pub fn forward(&mut self, packet: &super::packet::Packet, skipped: bool, output: &mut Vec<super::packet::Packet>) {
+    let pid = super::packet::pid(packet);
+    let cc = super::packet::continuity_counter(packet);
+    let mut packet = *packet;
+
+    let counter = self.counters.entry(pid).or_insert(Counter { output: cc.wrapping_sub(1) & 0x0F, source: None });
+    let mut restart = false;
+    if super::packet::has_payload(&packet) {
+      if let Some(source) = &counter.source {
+        let source_cc = super::packet::continuity_counter(source);
+        let indicator = super::packet::discontinuity_indicator(&packet);
+        let duplicate = source_cc == cc && source[super::packet::payload_offset(source)..] == packet[super::packet::payload_offset(&packet)..];
+        if !skipped && !indicator && duplicate { return; }
+        restart = skipped || indicator || (source_cc + 1) & 0x0F != cc;
+      }
+      counter.source = Some(packet);
+    }
+
+    if restart && !super::packet::set_discontinuity_indicator(&mut packet) {
+      // without payload, continuity_counter stays that of the last packet with payload
+      let mut indicator = [super::packet::STUFFING_BYTE; super::packet::PACKET_SIZE];
+      indicator[0..6].copy_from_slice(&[super::packet::SYNC_BYTE, packet[1] & 0x1F, packet[2], 0x20 | counter.output, 183, 0x80]);
+      output.push(indicator);
+    }
+    self.stamp(&mut packet);
+    output.push(packet);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn packet(pid: u16, cc: u8) -> super::super::packet::Packet {
+    let mut packet = [0u8; super::super::packet::PACKET_SIZE];
+    packet[0..4].copy_from_slice(&[0x47, (pid >> 8) as u8, pid as u8, 0x10 | cc]);
+    return packet;
+  }
+
+  #[test]
+  fn stamp_continues_per_pid() {
+    let mut counters = ContinuityCounters::new();
+    let mut stamped = Vec::new();
+    for pid in [0x100, 0x100, 0x200, 0x100] {
+      let mut packet = packet(pid, 0x0F);
+      counters.stamp(&mut packet);
+      stamped.push(super::super::packet::continuity_counter(&packet));
+    }
+    assert_eq!(stamped, vec![0, 1, 0, 2]);
+    assert_eq!(counters.next(0x100), 3);
+  }
+
+  #[test]
+  fn forward_drops_duplicates_and_flags_gaps() {
+    let mut counters = ContinuityCounters::new();
+    // the output already has packets of the PID, forwarded ones continue from them
+    counters.stamp(&mut packet(0x130, 0));
+
+    let mut output = Vec::new();
+    for cc in [5, 5, 6, 9] {
+      counters.forward(&packet(0x130, cc), false, &mut output);
+    }
+
+    let ccs = output.iter().map(super::super::packet::continuity_counter).collect::<Vec<_>>();
+    assert_eq!(ccs, vec![1, 2, 2, 3]);
+    // the gap 6 -> 9 is flagged on an adaptation field only packet, as the packet itself has no room
+    assert!(!super::super::packet::discontinuity_indicator(&output[1]));
+    assert!(!super::super::packet::has_payload(&output[2]));
+    assert!(super::super::packet::discontinuity_indicator(&output[2]));
+    assert!(!super::super::packet::discontinuity_indicator(&output[3]));
+  }
+
+  #[test]
+  fn forward_flags_gap_in_place() {
+    let mut counters = ContinuityCounters::new();
+    let mut output = Vec::new();
+    counters.forward(&packet(0x130, 3), false, &mut output);
+
+    let mut stuffed = packet(0x130, 7);
+    stuffed[3] |= 0x20;
+    stuffed[4] = 10;
+    counters.forward(&stuffed, false, &mut output);
+
+    assert_eq!(output.len(), 2);
+    assert!(super::super::packet::discontinuity_indicator(&output[1]));
+    assert_eq!(super::super::packet::continuity_counter(&output[1]), 4);
+  }
+
+  #[test]
+  fn forward_flags_skipped_packets() {
+    let mut counters = ContinuityCounters::new();
+    let mut output = Vec::new();
+    counters.forward(&packet(0x130, 3), false, &mut output);
+    // 15 packets cut away, the next one has the same continuity_counter but is no duplicate
+    counters.forward(&packet(0x130, 3), true, &mut output);
+    // 16 packets cut away, the next one looks continuous
+    counters.forward(&packet(0x130, 4), true, &mut output);
+    // a different payload on the same continuity_counter is no duplicate either
+    let mut changed = packet(0x130, 4);
+    changed[4] = 0x01;
+    counters.forward(&changed, false, &mut output);
+
+    let ccs = output.iter().map(super::super::packet::continuity_counter).collect::<Vec<_>>();
+    assert_eq!(ccs, vec![3, 3, 4, 4, 5, 5, 6]);
+    let flagged = output.iter().map(super::super::packet::discontinuity_indicator).collect::<Vec<_>>();
+    assert_eq!(flagged, vec![false, true, false, true, false, true, false]);
+  }
+}
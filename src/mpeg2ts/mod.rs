@@ -1,4 +1,9 @@
 pub mod packet;
 pub mod section;
+pub mod pes;
+pub mod pat;
+pub mod pmt;
 pub mod reader;
 pub mod packetize;
+pub mod continuity;
+pub mod writer;
@@ -1,9 +1,15 @@
 pub const PACKET_SIZE: usize = 188;
+pub const TIMESTAMPED_PACKET_SIZE: usize = 192;
+pub const FEC_PACKET_SIZE: usize = 204;
+pub const PACKET_SIZES: [usize; 3] = [PACKET_SIZE, TIMESTAMPED_PACKET_SIZE, FEC_PACKET_SIZE];
+pub const ARRIVAL_TIMESTAMP_SIZE: usize = 4;
+pub const ARRIVAL_TIMESTAMP_CLOCKS: u64 = 1 << 30;
 pub const HEADER_SIZE: usize = 4;
 pub const SYNC_BYTE: u8 = 0x47;
 pub const STUFFING_BYTE: u8 = 0xFF;
 pub const MPEGTS_TIMESTAMP_CLOCKS: u64 = 8589934592;
 pub const MPEGTS_TIMESTAMP_1SEC: u64 = 90000;
+pub const MPEGTS_SYSTEM_CLOCK_PER_TIMESTAMP: u64 = 300;
 
 pub type Packet = [u8; PACKET_SIZE];
 
@@ -51,9 +57,27 @@ pub fn adaptation_field_length(packet: &Packet) -> usize {
   }
 }
 
+// the pointer_field is the first byte of the payload, after the adaptation_field_length byte and the adaptation field
 pub fn pointer_field_offset(packet: &Packet) -> usize {
-  return HEADER_SIZE + adaptation_field_length(packet);
+  return payload_offset(packet);
 }
+
+pub fn payload_offset(packet: &Packet) -> usize {
+  if !has_adaptation_field(packet) { return HEADER_SIZE; }
+  return std::cmp::min(PACKET_SIZE, HEADER_SIZE + 1 + adaptation_field_length(packet));
+}
+
+pub fn discontinuity_indicator(packet: &Packet) -> bool {
+  return has_adaptation_field(packet) && adaptation_field_length(packet) != 0 && (packet[HEADER_SIZE + 1] & 0x80) != 0;
+}
+
+// only when the packet already has adaptation field flags to set it in, false otherwise
+pub fn set_discontinuity_indicator(packet: &mut Packet) -> bool {
+  if !has_adaptation_field(packet) || adaptation_field_length(packet) == 0 { return false; }
+  packet[HEADER_SIZE + 1] |= 0x80;
+  return true;
+}
+
 pub fn has_pcr(packet: &Packet) -> bool {
   return has_adaptation_field(packet) && adaptation_field_length(packet) != 0 && (packet[HEADER_SIZE + 1] & 0x10) != 0;
 }
@@ -70,3 +94,49 @@ pub fn pcr(packet: &Packet) -> Option<u64> {
 
   return Some(pcr_base);
 }
+
+pub fn pcr_extension(packet: &Packet) -> Option<u64> {
+  if !has_pcr(packet) { return None; }
+
+  let mut pcr_ext = 0u64;
+  pcr_ext = (pcr_ext << 1) | (((packet[HEADER_SIZE + 1 + 5] & 0x01) >> 0) as u64);
+  pcr_ext = (pcr_ext << 8) | (((packet[HEADER_SIZE + 1 + 6] & 0xFF) >> 0) as u64);
+
+  return Some(pcr_ext);
+}
+
+// difference of two 33bit timestamps, taking the wraparound into account
+pub fn timestamp_difference(lhs: u64, rhs: u64) -> i64 {
+  let difference = ((lhs + MPEGTS_TIMESTAMP_CLOCKS) - (rhs % MPEGTS_TIMESTAMP_CLOCKS)) % MPEGTS_TIMESTAMP_CLOCKS;
+  if difference >= MPEGTS_TIMESTAMP_CLOCKS / 2 {
+    return difference as i64 - MPEGTS_TIMESTAMP_CLOCKS as i64;
+  } else {
+    return difference as i64;
+  }
+}
+
+pub fn prefix_size(packet_size: usize) -> usize {
+  return if packet_size == TIMESTAMPED_PACKET_SIZE { ARRIVAL_TIMESTAMP_SIZE } else { 0 };
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn pointer_field_after_adaptation_field() {
+    let mut packet = [STUFFING_BYTE; PACKET_SIZE];
+    packet[0..4].copy_from_slice(&[SYNC_BYTE, 0x40, 0x00, 0x10]);
+    assert_eq!(pointer_field_offset(&packet), HEADER_SIZE);
+
+    // adaptation_field_length 2, and 2 bytes of adaptation field after it
+    packet[3] = 0x30;
+    packet[4..7].copy_from_slice(&[2, 0x00, STUFFING_BYTE]);
+    assert_eq!(pointer_field_offset(&packet), HEADER_SIZE + 3);
+
+    // adaptation field only, no payload to point into
+    packet[3] = 0x20;
+    packet[4] = 183;
+    assert_eq!(pointer_field_offset(&packet), PACKET_SIZE);
+  }
+}
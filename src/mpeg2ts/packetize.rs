@@ -29,3 +29,34 @@ pub fn packtize_section(section: &super::section::Section, pid: u16, continuity_
 
   return packets;
 }
+
+pub fn packetize_pes(pes: &super::pes::Pes, pid: u16, continuity_counter: u8) -> Vec<[u8; super::packet::PACKET_SIZE]> {
+  let mut packets = Vec::new();
+  let mut cc = continuity_counter;
+
+  let mut begin = 0;
+  while begin < pes.len() {
+    let mut packet = [0xFFu8; super::packet::PACKET_SIZE];
+    packet[0] = super::packet::SYNC_BYTE;
+    packet[1] = (if begin == 0 { 1 << 6 } else { 0 }) | (((pid & 0x1F00) >> 8) as u8);
+    packet[2] = ((pid & 0x00FF) >> 0) as u8;
+    packet[3] = (1 << 4) | (cc & 0x0F);
+    cc = (cc + 1) & 0x0F;
+
+    let next = min(pes.len(), begin + (super::packet::PACKET_SIZE - super::packet::HEADER_SIZE));
+    let length = next - begin;
+    let stuffing = (super::packet::PACKET_SIZE - super::packet::HEADER_SIZE) - length;
+    if stuffing > 0 {
+      // fill the remainder with adaptation field stuffing, PES can't be padded by 0xFF after its end
+      packet[3] |= 1 << 5;
+      packet[4] = (stuffing - 1) as u8;
+      if stuffing > 1 { packet[5] = 0; }
+    }
+    packet[super::packet::HEADER_SIZE + stuffing..].copy_from_slice(&pes[begin..next]);
+
+    packets.push(packet);
+    begin = next;
+  }
+
+  return packets;
+}
@@ -0,0 +1,62 @@
+pub const PROGRAM_SIZE: usize = 4;
+
+pub fn is_well_formed(pat: &super::section::Section) -> bool {
+  if !super::section::has_extended_header(pat) || super::section::table_id(pat) != 0x00 { return false; }
+
+  let loop_length = super::section::BASIC_HEADER_SIZE + super::section::section_length(pat) - super::section::CRC_SIZE - super::section::EXTENDED_HEADER_SIZE;
+  return loop_length.is_multiple_of(PROGRAM_SIZE);
+}
+
+// (program_number, program_map_PID) of the programs, without the network_PID entry
+pub fn programs(pat: &super::section::Section) -> Vec<(u16, u16)> {
+  let mut programs = Vec::new();
+  let mut begin = super::section::EXTENDED_HEADER_SIZE;
+  while begin + PROGRAM_SIZE <= super::section::BASIC_HEADER_SIZE + super::section::section_length(pat) - super::section::CRC_SIZE {
+    let program_number = ((pat[begin + 0] as u16) << 8) | ((pat[begin + 1] as u16) << 0);
+    let program_map_pid = (((pat[begin + 2] & 0x1F) as u16) << 8) | ((pat[begin + 3] as u16) << 0);
+    if program_number != 0 {
+      programs.push((program_number, program_map_pid));
+    }
+    begin += PROGRAM_SIZE;
+  }
+  return programs;
+}
+
+// network_PID of the program_number 0 entry, None when the PAT doesn't announce a NIT
+pub fn network_pid(pat: &super::section::Section) -> Option<u16> {
+  let mut begin = super::section::EXTENDED_HEADER_SIZE;
+  while begin + PROGRAM_SIZE <= super::section::BASIC_HEADER_SIZE + super::section::section_length(pat) - super::section::CRC_SIZE {
+    let program_number = ((pat[begin + 0] as u16) << 8) | ((pat[begin + 1] as u16) << 0);
+    if program_number == 0 {
+      return Some((((pat[begin + 2] & 0x1F) as u16) << 8) | ((pat[begin + 3] as u16) << 0));
+    }
+    begin += PROGRAM_SIZE;
+  }
+  return None;
+}
+
+// a PAT of `programs` (program_number, program_map_PID), with the NIT entry first when `network_pid` is given
+pub fn build(transport_stream_id: u16, version_number: u8, network_pid: Option<u16>, programs: &[(u16, u16)]) -> super::section::Section {
+  let mut pat: Vec<u8> = vec![0x00, 0xB0, 0x00, 0, 0, 0xC1 | ((version_number & 0x1F) << 1), 0x00, 0x00];
+  pat[3] = ((transport_stream_id & 0xFF00) >> 8) as u8;
+  pat[4] = ((transport_stream_id & 0x00FF) >> 0) as u8;
+
+  for (program_number, pid) in network_pid.map(|pid| (0, pid)).into_iter().chain(programs.iter().copied()) {
+    pat.push(((program_number & 0xFF00) >> 8) as u8);
+    pat.push(((program_number & 0x00FF) >> 0) as u8);
+    pat.push(0xE0 | ((pid & 0x1F00) >> 8) as u8);
+    pat.push(((pid & 0x00FF) >> 0) as u8);
+  }
+
+  // section length
+  pat[1] = (pat[1] & 0xF0) | (((pat.len() + super::section::CRC_SIZE - super::section::BASIC_HEADER_SIZE) & 0x0F00) >> 8) as u8;
+  pat[2] = (((pat.len() + super::section::CRC_SIZE - super::section::BASIC_HEADER_SIZE) & 0x00FF) >> 0) as u8;
+
+  // crc32
+  let crc32 = super::section::crc32(&pat);
+  pat.push(((crc32 & 0xFF000000u32) >> 24) as u8);
+  pat.push(((crc32 & 0x00FF0000u32) >> 16) as u8);
+  pat.push(((crc32 & 0x0000FF00u32) >> 8) as u8);
+  pat.push(((crc32 & 0x000000FFu32) >> 0) as u8);
+  return pat;
+}
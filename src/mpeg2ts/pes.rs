@@ -0,0 +1,130 @@
+pub const PES_HEADER_SIZE: usize = 6;
+pub const PES_OPTIONAL_HEADER_SIZE: usize = 3;
+pub const PES_TIMESTAMP_SIZE: usize = 5;
+
+pub type Pes = Vec<u8>;
+
+// PES header from the payload of a packet, which is enough to read PTS/DTS at payload_unit_start_indicator
+pub fn from_packet(packet: &super::packet::Packet) -> Pes {
+  return packet[super::packet::payload_offset(packet)..].to_vec();
+}
+
+// the accessors below give 0/false for fields beyond a PES header which continues into the next packet
+
+pub fn packet_start_code_prefix(pes: &Pes) -> u32 {
+  if pes.len() < 3 { return 0; }
+  return ((pes[0] as u32) << 16) | ((pes[1] as u32) << 8) | ((pes[2] as u32) << 0);
+}
+
+pub fn stream_id(pes: &Pes) -> u8 {
+  if pes.len() < 4 { return 0; }
+  return pes[3];
+}
+
+pub fn pes_packet_length(pes: &Pes) -> usize {
+  if pes.len() < PES_HEADER_SIZE { return 0; }
+  return (((pes[4] as u16) << 8) | ((pes[5] as u16) << 0)) as usize;
+}
+
+pub fn has_optional_header(pes: &Pes) -> bool {
+  if pes.len() < PES_HEADER_SIZE + PES_OPTIONAL_HEADER_SIZE || packet_start_code_prefix(pes) != 0x000001 { return false; }
+  return !matches!(stream_id(pes), 0xBC /* program_stream_map */ | 0xBE /* padding_stream */ | 0xBF /* private_stream_2 */ | 0xF0 /* ECM */ | 0xF1 /* EMM */ | 0xF2 /* DSMCC */ | 0xF8 /* H.222.1 type E */ | 0xFF /* program_stream_directory */);
+}
+
+pub fn pts_dts_flags(pes: &Pes) -> u8 {
+  if !has_optional_header(pes) { return 0; }
+  return (pes[PES_HEADER_SIZE + 1] & 0b11000000) >> 6;
+}
+
+pub fn pes_header_data_length(pes: &Pes) -> usize {
+  if !has_optional_header(pes) { return 0; }
+  return pes[PES_HEADER_SIZE + 2] as usize;
+}
+
+pub fn has_pts(pes: &Pes) -> bool {
+  return (pts_dts_flags(pes) & 0b10) != 0 && pes_header_data_length(pes) >= PES_TIMESTAMP_SIZE && pes.len() >= PES_HEADER_SIZE + PES_OPTIONAL_HEADER_SIZE + PES_TIMESTAMP_SIZE;
+}
+
+pub fn has_dts(pes: &Pes) -> bool {
+  return pts_dts_flags(pes) == 0b11 && pes_header_data_length(pes) >= PES_TIMESTAMP_SIZE * 2 && pes.len() >= PES_HEADER_SIZE + PES_OPTIONAL_HEADER_SIZE + PES_TIMESTAMP_SIZE * 2;
+}
+
+fn timestamp(pes: &Pes, begin: usize) -> u64 {
+  let mut timestamp = 0u64;
+  timestamp = (timestamp << 3) | (((pes[begin + 0] & 0x0E) >> 1) as u64);
+  timestamp = (timestamp << 8) | (((pes[begin + 1] & 0xFF) >> 0) as u64);
+  timestamp = (timestamp << 7) | (((pes[begin + 2] & 0xFE) >> 1) as u64);
+  timestamp = (timestamp << 8) | (((pes[begin + 3] & 0xFF) >> 0) as u64);
+  timestamp = (timestamp << 7) | (((pes[begin + 4] & 0xFE) >> 1) as u64);
+  return timestamp;
+}
+
+fn set_timestamp(pes: &mut Pes, begin: usize, timestamp: u64) {
+  pes[begin + 0] = (pes[begin + 0] & 0xF1) | ((((timestamp >> 30) & 0x07) << 1) as u8);
+  pes[begin + 1] = ((timestamp >> 22) & 0xFF) as u8;
+  pes[begin + 2] = (pes[begin + 2] & 0x01) | ((((timestamp >> 15) & 0x7F) << 1) as u8);
+  pes[begin + 3] = ((timestamp >> 7) & 0xFF) as u8;
+  pes[begin + 4] = (pes[begin + 4] & 0x01) | ((((timestamp >> 0) & 0x7F) << 1) as u8);
+}
+
+pub fn pts(pes: &Pes) -> Option<u64> {
+  if !has_pts(pes) { return None; }
+  return Some(timestamp(pes, PES_HEADER_SIZE + PES_OPTIONAL_HEADER_SIZE));
+}
+
+pub fn dts(pes: &Pes) -> Option<u64> {
+  if !has_dts(pes) { return None; }
+  return Some(timestamp(pes, PES_HEADER_SIZE + PES_OPTIONAL_HEADER_SIZE + PES_TIMESTAMP_SIZE));
+}
+
+pub fn set_pts(pes: &mut Pes, pts: u64) {
+  if !has_pts(pes) { return; }
+  set_timestamp(pes, PES_HEADER_SIZE + PES_OPTIONAL_HEADER_SIZE, pts);
+}
+
+pub fn set_dts(pes: &mut Pes, dts: u64) {
+  if !has_dts(pes) { return; }
+  set_timestamp(pes, PES_HEADER_SIZE + PES_OPTIONAL_HEADER_SIZE + PES_TIMESTAMP_SIZE, dts);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // a PES start with PTS and DTS, cut to `length` bytes as when its header continues into the next packet
+  fn pes_start(length: usize) -> Pes {
+    let mut pes = vec![0x00, 0x00, 0x01, 0xE0, 0x00, 0x00, 0x80, 0xC0, 0x0A, 0x31, 0x00, 0x01, 0x00, 0x01, 0x11, 0x00, 0x01, 0x00, 0x01];
+    pes.truncate(length);
+    return pes;
+  }
+
+  #[test]
+  fn reads_timestamps() {
+    let pes = pes_start(19);
+    assert_eq!(pts(&pes), Some(0));
+    assert_eq!(dts(&pes), Some(0));
+  }
+
+  #[test]
+  fn split_header_has_no_timestamps() {
+    for length in 0..19 {
+      let pes = pes_start(length);
+      assert_eq!(dts(&pes), None, "length {}", length);
+      if length < 14 { assert_eq!(pts(&pes), None, "length {}", length); }
+    }
+  }
+
+  #[test]
+  fn split_header_from_packet() {
+    // payload_unit_start_indicator with a 181 byte adaptation field, leaving 3 bytes of PES
+    let mut packet = [0xFFu8; super::super::packet::PACKET_SIZE];
+    packet[0..6].copy_from_slice(&[0x47, 0x41, 0x00, 0x30, 180, 0x00]);
+    packet[185..188].copy_from_slice(&[0x00, 0x00, 0x01]);
+
+    let pes = from_packet(&packet);
+    assert_eq!(pes.len(), 3);
+    assert_eq!(stream_id(&pes), 0);
+    assert!(!has_optional_header(&pes));
+    assert_eq!(pts(&pes), None);
+  }
+}
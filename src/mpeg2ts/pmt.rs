@@ -0,0 +1,46 @@
+pub fn is_video_stream_type(stream_type: u8) -> bool {
+  return matches!(stream_type, 0x01 /* MPEG1 Video */ | 0x02 /* MPEG2 Video */ | 0x10 /* MPEG4 Visual */ | 0x1B /* H.264 */ | 0x24 /* H.265 */);
+}
+
+pub fn is_audio_stream_type(stream_type: u8) -> bool {
+  return matches!(stream_type, 0x03 /* MPEG1 Audio */ | 0x04 /* MPEG2 Audio */ | 0x0F /* AAC ADTS */ | 0x11 /* AAC LATM */ | 0x81 /* AC-3 */);
+}
+
+pub fn is_data_stream_type(stream_type: u8) -> bool {
+  return matches!(stream_type, 0x06 /* PES private data */ | 0x0D /* DSM-CC */);
+}
+
+pub fn is_well_formed(pmt: &super::section::Section) -> bool {
+  if !super::section::has_extended_header(pmt) || super::section::table_id(pmt) != 0x02 { return false; }
+  let end = super::section::BASIC_HEADER_SIZE + super::section::section_length(pmt) - super::section::CRC_SIZE;
+  if end < super::section::EXTENDED_HEADER_SIZE + 4 { return false; }
+
+  let program_info_length = ((((pmt[super::section::EXTENDED_HEADER_SIZE + 2] & 0x0F) as u16) << 8) | ((pmt[super::section::EXTENDED_HEADER_SIZE + 3] as u16) << 0)) as usize;
+  let mut begin = super::section::EXTENDED_HEADER_SIZE + 4 + program_info_length;
+  while begin < end {
+    if begin + 5 > end { return false; }
+    let es_info_length = (((pmt[begin + 3] & 0x0F) as usize) << 8) | ((pmt[begin + 4] as usize) << 0);
+    begin += 5 + es_info_length;
+  }
+  return begin == end;
+}
+
+// (descriptor_tag, descriptor body) of a descriptor loop, stopping at a descriptor which overruns the loop
+pub fn descriptors(descriptors: &[u8]) -> Vec<(u8, &[u8])> {
+  let mut result = Vec::new();
+  let mut offset = 0;
+  while offset + 2 <= descriptors.len() {
+    let tag = descriptors[offset + 0];
+    let length = descriptors[offset + 1] as usize;
+    if offset + 2 + length > descriptors.len() { break; }
+
+    result.push((tag, &descriptors[offset + 2 .. offset + 2 + length]));
+    offset += 2 + length;
+  }
+  return result;
+}
+
+// component_tag of the stream_identifier_descriptor in an ES info loop
+pub fn component_tag(es_info: &[u8]) -> Option<u8> {
+  return descriptors(es_info).into_iter().find(|(tag, body)| *tag == 0x52 && !body.is_empty()).map(|(_, body)| body[0]);
+}
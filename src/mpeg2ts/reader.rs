@@ -1,91 +1,603 @@
 use std::io::{BufReader, Read};
-use std::{collections::VecDeque, cmp::min};
+use std::{collections::{HashMap, VecDeque}, cmp::min};
+
+use crate::error::{Error, Result};
+
+const DETECT_PACKETS: usize = 8;
+const SYNC_CONFIRM_PACKETS: usize = 4;
+const READ_CHUNK_SIZE: usize = 65536;
+// a larger step of arrival timestamps than this (1s in 27MHz) is a break, which the arrival clock does not follow
+const ARRIVAL_JUMP_LIMIT: u64 = 27_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncLoss {
+  pub offset: u64,
+  pub skipped: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacketStatistics {
+  pub packets: u64,
+  pub sync_losses: u64,
+  pub skipped_bytes: u64,
+}
 
 pub struct PacketReader<R> {
-  reader: BufReader<R>
+  reader: BufReader<R>,
+  buffer: Vec<u8>,
+  begin: usize,
+  eof: bool,
+  packet_size: Option<usize>,
+
+  position: u64,
+  synchronized: bool,
+  skip_offset: u64,
+  skipped: u64,
+  losses: VecDeque<SyncLoss>,
+  statistics: PacketStatistics,
+
+  // arrival clock in 27MHz of the last packet, from the arrival timestamps of 192 byte packets, and the last timestamp
+  arrival_clock: Option<u64>,
+  arrival_timestamp: u64,
 }
 
 impl<R: Read> PacketReader<R> {
   pub fn new(inner: R) -> Self {
-    return PacketReader { reader: BufReader::new(inner) }
+    return PacketReader {
+      reader: BufReader::new(inner),
+      buffer: Vec::new(),
+      begin: 0,
+      eof: false,
+      packet_size: None,
+      position: 0,
+      synchronized: false,
+      skip_offset: 0,
+      skipped: 0,
+      losses: VecDeque::new(),
+      statistics: PacketStatistics::default(),
+      arrival_clock: None,
+      arrival_timestamp: 0,
+    }
+  }
+
+  // continued over the wraparound of the timestamps and over their jumps, None without timestamps
+  pub fn arrival_clock(&self) -> Option<u64> {
+    return self.arrival_clock;
   }
 
-  pub fn read(&mut self) -> std::io::Result<super::packet::Packet> {
+  pub fn packet_size(&self) -> Option<usize> {
+    return self.packet_size;
+  }
+
+  pub fn statistics(&self) -> PacketStatistics {
+    return self.statistics;
+  }
+
+  pub fn pop_sync_loss(&mut self) -> Option<SyncLoss> {
+    return self.losses.pop_front();
+  }
+
+  fn available(&self) -> usize {
+    return self.buffer.len() - self.begin;
+  }
+
+  fn fill(&mut self, length: usize) -> std::io::Result<()> {
+    if self.begin >= READ_CHUNK_SIZE {
+      self.buffer.drain(..self.begin);
+      self.begin = 0;
+    }
+
+    while !self.eof && self.available() < length {
+      let mut chunk = [0u8; READ_CHUNK_SIZE];
+      match self.reader.read(&mut chunk) {
+        Ok(0) => { self.eof = true; }
+        Ok(n) => { self.buffer.extend(&chunk[..n]); }
+        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+        Err(e) => { return Err(e); }
+      }
+    }
+
+    return Ok(());
+  }
+
+  // sync bytes must repeat `count` times with the packet period (or until the end of data) to be trusted
+  fn is_synchronized_at(&self, offset: usize, packet_size: usize, count: usize) -> bool {
+    let prefix = super::packet::prefix_size(packet_size);
+    let mut checked = 0;
+    while checked < count && offset + checked * packet_size + prefix < self.available() {
+      if self.buffer[self.begin + offset + checked * packet_size + prefix] != super::packet::SYNC_BYTE { return false; }
+      checked += 1;
+    }
+    return checked > 0;
+  }
+
+  fn skip(&mut self, length: usize) {
+    if self.skipped == 0 {
+      self.skip_offset = self.position;
+    }
+    self.begin += length;
+    self.position += length as u64;
+    self.skipped += length as u64;
+    self.synchronized = false;
+  }
+
+  fn report_skipped(&mut self) {
+    if self.skipped == 0 { return; }
+
+    self.losses.push_back(SyncLoss { offset: self.skip_offset, skipped: self.skipped });
+    self.statistics.sync_losses += 1;
+    self.statistics.skipped_bytes += self.skipped;
+    self.skipped = 0;
+  }
+
+  // find the first offset where sync bytes repeat with the period of one of the known packet sizes
+  fn detect(&mut self) -> Result<usize> {
+    self.fill(super::packet::FEC_PACKET_SIZE * (DETECT_PACKETS + 1)).map_err(Error::Read)?;
+
+    for offset in 0..self.available() {
+      if self.buffer[self.begin + offset] != super::packet::SYNC_BYTE { continue; }
+
+      for packet_size in super::packet::PACKET_SIZES {
+        let prefix = super::packet::prefix_size(packet_size);
+        let count = min(DETECT_PACKETS, (self.available() - offset - 1) / packet_size + 1);
+        if count < min(DETECT_PACKETS, self.available() / packet_size) { continue; }
+        if offset < prefix { continue; }
+        if !self.is_synchronized_at(offset - prefix, packet_size, count) { continue; }
+
+        self.skip(offset - prefix);
+        return Ok(packet_size);
+      }
+    }
+
+    return Ok(super::packet::PACKET_SIZE);
+  }
+
+  // Ok(None) at a clean end of file, Err(TruncatedPacket) when the last packet is cut short
+  pub fn read(&mut self) -> Result<Option<super::packet::Packet>> {
+    let packet_size = match self.packet_size {
+      Some(packet_size) => packet_size,
+      None => {
+        let packet_size = self.detect()?;
+        self.packet_size = Some(packet_size);
+        packet_size
+      }
+    };
+    let prefix = super::packet::prefix_size(packet_size);
+
     loop {
-      let mut syncbyte = [0u8; 1];
-      self.reader.read_exact(&mut syncbyte)?;
-      if syncbyte[0] != super::packet::SYNC_BYTE { continue }
+      self.fill(packet_size * SYNC_CONFIRM_PACKETS).map_err(Error::Read)?;
+      if self.available() < packet_size {
+        if self.synchronized && self.available() > prefix && self.buffer[self.begin + prefix] == super::packet::SYNC_BYTE {
+          let (offset, length) = (self.position, self.available());
+          self.begin += length;
+          self.position += length as u64;
+          return Err(Error::TruncatedPacket { offset, length });
+        }
 
-      let mut payload: [u8; super::packet::PACKET_SIZE - 1] = [0u8; super::packet::PACKET_SIZE - 1];
-      self.reader.read_exact(&mut payload)?;
+        self.skip(self.available());
+        self.report_skipped();
+        return Ok(None);
+      }
+
+      let confirm = if self.synchronized { 1 } else { SYNC_CONFIRM_PACKETS };
+      if !self.is_synchronized_at(0, packet_size, confirm) {
+        self.skip(1);
+        continue;
+      }
+      self.report_skipped();
+      self.synchronized = true;
+
+      if prefix == super::packet::ARRIVAL_TIMESTAMP_SIZE {
+        let bytes = [self.buffer[self.begin], self.buffer[self.begin + 1], self.buffer[self.begin + 2], self.buffer[self.begin + 3]];
+        let timestamp = u32::from_be_bytes(bytes) as u64 % super::packet::ARRIVAL_TIMESTAMP_CLOCKS;
+        let difference = (timestamp + super::packet::ARRIVAL_TIMESTAMP_CLOCKS - self.arrival_timestamp) % super::packet::ARRIVAL_TIMESTAMP_CLOCKS;
+        self.arrival_clock = Some(match self.arrival_clock {
+          Some(clock) if difference <= ARRIVAL_JUMP_LIMIT => clock + difference,
+          Some(clock) => clock,
+          None => timestamp,
+        });
+        self.arrival_timestamp = timestamp;
+      }
 
       let mut packet: super::packet::Packet = [0u8; super::packet::PACKET_SIZE];
-      packet[..1].copy_from_slice(&syncbyte);
-      packet[1..].copy_from_slice(&payload);
+      packet.copy_from_slice(&self.buffer[self.begin + prefix .. self.begin + prefix + super::packet::PACKET_SIZE]);
+      self.begin += packet_size;
+      self.position += packet_size as u64;
+      self.statistics.packets += 1;
 
-      return Ok(packet);
+      return Ok(Some(packet));
     }
   }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionEvent {
+  CrcError { pid: u16, table_id: u8 },
+  // a section the reader was told to expect a structure of, without section_syntax_indicator or with a broken one
+  Malformed { pid: u16, table_id: u8 },
+  // current_next_indicator 0, reported once per version of the table
+  NotCurrent { pid: u16, table_id: u8, table_id_extension: u16, version: u8 },
+  VersionChanged { pid: u16, table_id: u8, table_id_extension: u16, previous: u8, current: u8 },
+  // packets were lost, `discarded` tells a partial section was thrown away with them
+  ContinuityGap { pid: u16, expected: u8, actual: u8, discarded: bool },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SectionStatistics {
+  pub sections: u64,
+  pub crc_errors: u64,
+  pub malformed: u64,
+  pub not_current: u64,
+  pub version_changes: u64,
+  pub unchanged: u64,
+  pub continuity_gaps: u64,
+  pub duplicates: u64,
+  pub discontinuities: u64,
+}
+
 pub struct SectionReader {
   section: Option<Vec<u8>>,
-  queue: VecDeque<super::section::Section>
+  queue: VecDeque<super::section::Section>,
+
+  pid: u16,
+  changes_only: bool,
+  well_formed: Option<fn(&super::section::Section) -> bool>,
+  // (table_id, table_id_extension, section_number) => last section yielded
+  sections: HashMap<(u8, u16, u8), super::section::Section>,
+  // (table_id, table_id_extension, section_number) => version of the last not yet applicable section
+  not_current: HashMap<(u8, u16, u8), u8>,
+  events: VecDeque<SectionEvent>,
+  statistics: SectionStatistics,
+  continuity_counters: HashMap<u16, u8>,
+}
+
+impl Default for SectionReader {
+  fn default() -> Self {
+    return Self::new();
+  }
 }
 
 impl SectionReader {
   pub fn new() -> Self {
-    return SectionReader { section: None, queue: VecDeque::new() }
+    return SectionReader {
+      section: None,
+      queue: VecDeque::new(),
+      pid: 0,
+      changes_only: false,
+      well_formed: None,
+      sections: HashMap::new(),
+      not_current: HashMap::new(),
+      events: VecDeque::new(),
+      statistics: SectionStatistics::default(),
+      continuity_counters: HashMap::new(),
+    }
   }
 
-  pub fn push(&mut self, packet: &super::packet::Packet) {
-    let mut begin: usize = super::packet::pointer_field_offset(packet);
+  // only yield a section when its content differs from the last one with the same table_id/table_id_extension/section_number
+  pub fn set_changes_only(&mut self, changes_only: bool) {
+    self.changes_only = changes_only;
+  }
 
-    if super::packet::payload_unit_start_indicator(packet) {
-      if self.section.is_none() {
-        begin += packet[super::packet::pointer_field_offset(packet)] as usize;
-      }
-      begin += 1;
-    }
+  // only yield sections with section_syntax_indicator which pass the check, e.g. pat::is_well_formed for PID 0
+  pub fn set_well_formed(&mut self, well_formed: fn(&super::section::Section) -> bool) {
+    self.well_formed = Some(well_formed);
+  }
 
-    if !super::packet::payload_unit_start_indicator(packet) {
-      if let Some(ref mut section) = self.section {
-        let next = min(super::packet::PACKET_SIZE, begin + (super::section::BASIC_HEADER_SIZE + super::section::section_length(&section)) - section.len());
-        section.extend(&packet[begin..next]);
+  pub fn statistics(&self) -> SectionStatistics {
+    return self.statistics;
+  }
 
-        if section.len() == (super::section::BASIC_HEADER_SIZE + super::section::section_length(&section)) {
-          self.queue.push_back(section.to_vec());
-        }
-        if section.len() >= (super::section::BASIC_HEADER_SIZE + super::section::section_length(&section)) {
-          self.section = None;
-        }
+  pub fn pop_event(&mut self) -> Option<SectionEvent> {
+    return self.events.pop_front();
+  }
+
+  fn complete(&mut self, section: super::section::Section) {
+    if section.len() < super::section::BASIC_HEADER_SIZE { return; }
+    self.statistics.sections += 1;
+
+    if !super::section::section_syntax_indicator(&section) {
+      if self.well_formed.is_some() {
+        self.statistics.malformed += 1;
+        self.events.push_back(SectionEvent::Malformed { pid: self.pid, table_id: super::section::table_id(&section) });
+        return;
+      }
+      self.queue.push_back(section);
+      return;
+    }
+    if !super::section::has_extended_header(&section) || !super::section::is_crc_valid(&section) {
+      self.statistics.crc_errors += 1;
+      self.events.push_back(SectionEvent::CrcError { pid: self.pid, table_id: super::section::table_id(&section) });
+      return;
+    }
+    if self.well_formed.is_some_and(|well_formed| !well_formed(&section)) {
+      self.statistics.malformed += 1;
+      self.events.push_back(SectionEvent::Malformed { pid: self.pid, table_id: super::section::table_id(&section) });
+      return;
+    }
+    let key = (super::section::table_id(&section), super::section::table_id_extension(&section), super::section::section_number(&section));
+    if !super::section::current_next_indicator(&section) {
+      self.statistics.not_current += 1;
+      let version = super::section::version_number(&section);
+      if self.not_current.insert(key, version) != Some(version) {
+        self.events.push_back(SectionEvent::NotCurrent { pid: self.pid, table_id: key.0, table_id_extension: key.1, version });
       }
-    } else {
-      while begin < super::packet::PACKET_SIZE {
-        if packet[begin] == super::packet::STUFFING_BYTE { return; }
-
-        let section = self.section.get_or_insert(vec![] as super::section::Section);
-        let next = min(super::packet::PACKET_SIZE, if section.len() == 0 {
-          begin + ((((packet[begin + 1] as u16) & 0x0F) << 8) | ((packet[begin + 2] as u16) << 0)) as usize
-        } else {
-          begin + (super::section::BASIC_HEADER_SIZE + super::section::section_length(&section)) - section.len()
+      return;
+    }
+    if let Some(previous) = self.sections.get(&key) {
+      if super::section::version_number(previous) != super::section::version_number(&section) {
+        self.statistics.version_changes += 1;
+        self.events.push_back(SectionEvent::VersionChanged {
+          pid: self.pid,
+          table_id: key.0,
+          table_id_extension: key.1,
+          previous: super::section::version_number(previous),
+          current: super::section::version_number(&section),
         });
+      } else if self.changes_only && *previous == section {
+        self.statistics.unchanged += 1;
+        return;
+      }
+    }
 
-        section.extend(&packet[begin..next]);
+    self.sections.insert(key, section.clone());
+    self.queue.push_back(section);
+  }
 
-        if section.len() == (super::section::BASIC_HEADER_SIZE + super::section::section_length(&section)) {
-          self.queue.push_back(section.to_vec());
-        }
-        if section.len() >= (super::section::BASIC_HEADER_SIZE + super::section::section_length(&section)) {
-          self.section = None;
-        }
+  // false when the packet should not be used, a duplicate or one without payload
+  fn check_continuity(&mut self, packet: &super::packet::Packet) -> bool {
+    if !super::packet::has_payload(packet) { return false; }
+
+    let cc = super::packet::continuity_counter(packet);
+    let Some(previous) = self.continuity_counters.insert(self.pid, cc) else { return true; };
+
+    if super::packet::discontinuity_indicator(packet) {
+      self.statistics.discontinuities += 1;
+      return true;
+    }
+    if cc == previous {
+      self.statistics.duplicates += 1;
+      return false;
+    }
+
+    let expected = (previous + 1) & 0x0F;
+    if cc != expected {
+      let discarded = self.section.take().is_some();
+      self.statistics.continuity_gaps += 1;
+      self.events.push_back(SectionEvent::ContinuityGap { pid: self.pid, expected, actual: cc, discarded });
+    }
+    return true;
+  }
 
-        begin = next;
+  // appends packet[begin..end] to the section being read until it is whole, returns where it stopped
+  fn fill(&mut self, packet: &super::packet::Packet, begin: usize, end: usize) -> usize {
+    let Some(ref mut section) = self.section else { return end; };
+    let mut begin = begin;
+
+    // the length is unknown until the 3 byte header is there, which may span two packets
+    if section.len() < super::section::BASIC_HEADER_SIZE {
+      let next = min(end, begin + super::section::BASIC_HEADER_SIZE - section.len());
+      section.extend(&packet[begin..next]);
+      begin = next;
+      if section.len() < super::section::BASIC_HEADER_SIZE { return begin; }
+    }
+
+    let length = super::section::BASIC_HEADER_SIZE + super::section::section_length(section);
+    let next = min(end, begin + length - section.len());
+    section.extend(&packet[begin..next]);
+
+    if section.len() == length {
+      if let Some(completed) = self.section.take() {
+        self.complete(completed);
       }
     }
+    return next;
+  }
+
+  pub fn push(&mut self, packet: &super::packet::Packet) {
+    self.pid = super::packet::pid(packet);
+    if !self.check_continuity(packet) { return; }
+    let mut begin: usize = super::packet::pointer_field_offset(packet);
+    if begin >= super::packet::PACKET_SIZE { return; }
+
+    if !super::packet::payload_unit_start_indicator(packet) {
+      self.fill(packet, begin, super::packet::PACKET_SIZE);
+      return;
+    }
+
+    let pointer_field = packet[begin] as usize;
+    begin += 1;
+    // the bytes up to the pointer finish the section carried over from earlier packets
+    let pointer = min(super::packet::PACKET_SIZE, begin + pointer_field);
+    self.fill(packet, begin, pointer);
+    self.section = None;
+
+    begin = pointer;
+    while begin < super::packet::PACKET_SIZE {
+      if packet[begin] == super::packet::STUFFING_BYTE { return; }
+
+      self.section = Some(Vec::new());
+      begin = self.fill(packet, begin, super::packet::PACKET_SIZE);
+    }
   }
 
   pub fn pop(&mut self) -> Option<super::section::Section> {
     return self.queue.pop_front();
   }
 }
+
+pub struct PesReader {
+  pes: Option<Vec<u8>>,
+  queue: VecDeque<super::pes::Pes>
+}
+
+impl Default for PesReader {
+  fn default() -> Self {
+    return Self::new();
+  }
+}
+
+impl PesReader {
+  pub fn new() -> Self {
+    return PesReader { pes: None, queue: VecDeque::new() }
+  }
+
+  fn complete(&mut self) {
+    let Some(pes) = self.pes.take() else { return; };
+    if pes.len() < super::pes::PES_HEADER_SIZE { return; }
+    if super::pes::packet_start_code_prefix(&pes) != 0x000001 { return; }
+
+    // unbounded PES (PES_packet_length == 0) ends at the next payload_unit_start_indicator
+    if super::pes::pes_packet_length(&pes) == 0 || pes.len() == super::pes::PES_HEADER_SIZE + super::pes::pes_packet_length(&pes) {
+      self.queue.push_back(pes);
+    }
+  }
+
+  pub fn push(&mut self, packet: &super::packet::Packet) {
+    if !super::packet::has_payload(packet) { return; }
+    let begin = super::packet::payload_offset(packet);
+
+    if super::packet::payload_unit_start_indicator(packet) {
+      self.complete();
+      self.pes = Some(Vec::new());
+    }
+
+    let Some(ref mut pes) = self.pes else { return; };
+    pes.extend(&packet[begin..]);
+
+    if pes.len() >= super::pes::PES_HEADER_SIZE && super::pes::pes_packet_length(pes) != 0 {
+      let length = super::pes::PES_HEADER_SIZE + super::pes::pes_packet_length(pes);
+      if pes.len() >= length {
+        pes.truncate(length);
+        self.complete();
+      }
+    }
+  }
+
+  pub fn pop(&mut self) -> Option<super::pes::Pes> {
+    return self.queue.pop_front();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // a section with the extended header and CRC_32, `size` bytes in total
+  fn section(table_id_extension: u16, size: usize) -> super::super::section::Section {
+    let mut section = vec![0u8; size - super::super::section::CRC_SIZE];
+    let section_length = size - super::super::section::BASIC_HEADER_SIZE;
+    section[0..8].copy_from_slice(&[0x00, 0xB0 | (section_length >> 8) as u8, section_length as u8, (table_id_extension >> 8) as u8, table_id_extension as u8, 0xC1, 0x00, 0x00]);
+    let crc = super::super::section::crc32(&section);
+    section.extend(crc.to_be_bytes());
+    return section;
+  }
+
+  fn packet(pid: u16, pusi: bool, cc: u8, payload: &[u8]) -> super::super::packet::Packet {
+    let mut packet = [0xFFu8; super::super::packet::PACKET_SIZE];
+    packet[0..4].copy_from_slice(&[0x47, (if pusi { 0x40 } else { 0x00 }) | (pid >> 8) as u8, pid as u8, 0x10 | cc]);
+    packet[4..4 + payload.len()].copy_from_slice(payload);
+    return packet;
+  }
+
+  #[test]
+  fn header_split_across_packets() {
+    // the second section starts at byte 186 and 187, its header ends in the next packet
+    for first_size in [181, 182] {
+      let first = section(1, first_size);
+      let second = section(2, 20);
+      let split = super::super::packet::PACKET_SIZE - 5 - first_size;
+
+      let mut payload = vec![0x00];
+      payload.extend(&first);
+      payload.extend(&second[..split]);
+
+      let mut reader = SectionReader::new();
+      reader.push(&packet(0, true, 0, &payload));
+      reader.push(&packet(0, false, 1, &second[split..]));
+
+      assert_eq!(reader.pop(), Some(first));
+      assert_eq!(reader.pop(), Some(second));
+      assert_eq!(reader.pop(), None);
+    }
+  }
+
+  #[test]
+  fn broken_psi_is_dropped() {
+    let mut without_syntax = section(1, 16);
+    without_syntax[1] &= 0x7F;
+    let mut crc_error = section(1, 16);
+    crc_error[10] ^= 0xFF;
+    // a PAT with half a program entry
+    let truncated = section(1, 18);
+    let pat = section(1, 16);
+
+    let mut reader = SectionReader::new();
+    reader.set_well_formed(super::super::pat::is_well_formed);
+    let mut payload = vec![0x00];
+    for section in [&without_syntax, &crc_error, &truncated, &pat] {
+      payload.extend(section);
+    }
+    reader.push(&packet(0, true, 0, &payload));
+
+    assert_eq!(reader.pop(), Some(pat));
+    assert_eq!(reader.pop(), None);
+    assert_eq!(reader.statistics().malformed, 2);
+    assert_eq!(reader.statistics().crc_errors, 1);
+    assert_eq!(reader.pop_event(), Some(SectionEvent::Malformed { pid: 0, table_id: 0x00 }));
+  }
+
+  #[test]
+  fn not_current_is_reported_once() {
+    let mut next = section(1, 16);
+    next[5] = 0xC2;
+    let crc = super::super::section::crc32(&next[..12].to_vec());
+    next[12..16].copy_from_slice(&crc.to_be_bytes());
+
+    let mut reader = SectionReader::new();
+    let mut payload = vec![0x00];
+    payload.extend(&next);
+    payload.extend(&next);
+    reader.push(&packet(0, true, 0, &payload));
+
+    assert_eq!(reader.pop(), None);
+    assert_eq!(reader.statistics().not_current, 2);
+    assert_eq!(reader.pop_event(), Some(SectionEvent::NotCurrent { pid: 0, table_id: 0x00, table_id_extension: 1, version: 1 }));
+    assert_eq!(reader.pop_event(), None);
+  }
+
+  #[test]
+  fn section_after_adaptation_field() {
+    let pat = section(1, 16);
+    // adaptation field with flags and one stuffing byte before the pointer_field
+    let mut payload = vec![0x02, 0x00, 0xFF, 0x00];
+    payload.extend(&pat);
+    let mut packet = packet(0, true, 0, &payload);
+    packet[3] |= 0x20;
+
+    let mut reader = SectionReader::new();
+    reader.push(&packet);
+    assert_eq!(reader.pop(), Some(pat));
+    assert_eq!(reader.statistics().crc_errors, 0);
+  }
+
+  #[test]
+  fn pointer_field_ends_carried_section() {
+    let first = section(1, 200);
+    let second = section(2, 20);
+    let carried = first.len() - 183;
+
+    let mut payload = vec![0x00];
+    payload.extend(&first[..183]);
+    let mut reader = SectionReader::new();
+    reader.push(&packet(0, true, 0, &payload));
+
+    let mut payload = vec![carried as u8];
+    payload.extend(&first[183..]);
+    payload.extend(&second);
+    reader.push(&packet(0, true, 1, &payload));
+
+    assert_eq!(reader.pop(), Some(first));
+    assert_eq!(reader.pop(), Some(second));
+    assert_eq!(reader.pop(), None);
+  }
+}
@@ -8,6 +8,10 @@ pub fn table_id(section: &Section) -> u8 {
   return section[0];
 }
 
+pub fn section_syntax_indicator(section: &Section) -> bool {
+  return (section[1] & 0x80) != 0;
+}
+
 pub fn section_length(section: &Section) -> usize {
   return ((((section[1] & 0x0F) as u16) << 8) | ((section[2] as u16) << 0)) as usize;
 }
@@ -16,10 +20,22 @@ pub fn table_id_extension(section: &Section) -> u16 {
   return ((section[3] as u16) << 8) | ((section[4] as u16) << 0);
 }
 
+pub fn version_number(section: &Section) -> u8 {
+  return (section[5] & 0x3E) >> 1;
+}
+
 pub fn current_next_indicator(section: &Section) -> bool {
   return (section[5] & 0x01) != 0;
 }
 
+pub fn section_number(section: &Section) -> u8 {
+  return section[6];
+}
+
+pub fn last_section_number(section: &Section) -> u8 {
+  return section[7];
+}
+
 pub fn crc32(section: &Section) -> u32 {
   let mut crc = 0xFFFFFFFFu32;
   for byte in section {
@@ -36,3 +52,18 @@ pub fn crc32(section: &Section) -> u32 {
   return crc;
 }
 
+
+// CRC_32 makes the CRC over the whole section zero, when the section is intact
+pub fn is_crc_valid(section: &Section) -> bool {
+  return crc32(section) == 0;
+}
+
+pub fn has_extended_header(section: &Section) -> bool {
+  return section.len() >= EXTENDED_HEADER_SIZE + CRC_SIZE && section.len() == BASIC_HEADER_SIZE + section_length(section);
+}
+
+pub fn validate(section: &Section, pid: u16) -> crate::error::Result<()> {
+  if !has_extended_header(section) { return Err(crate::error::Error::MalformedSection { pid }); }
+  if !is_crc_valid(section) { return Err(crate::error::Error::Crc { pid }); }
+  return Ok(());
+}
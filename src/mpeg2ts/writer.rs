@@ -0,0 +1,176 @@
+use std::io::{BufWriter, Write};
+
+// packets held for their arrival timestamps at most, which are then given on at the last pace
+const PENDING_LIMIT: usize = 16384;
+
+pub struct PacketWriter<W: Write> {
+  writer: BufWriter<W>,
+  packet_size: usize,
+
+  // packets waiting for the arrival clock to move on, their timestamps are spread from where it was up to where it went
+  pending: Vec<super::packet::Packet>,
+  // arrival clock in 27MHz where the pending packets start, and the clock per packet of the last spread
+  arrival_clock: Option<u64>,
+  step: u64,
+}
+
+impl<W: Write> PacketWriter<W> {
+  pub fn new(inner: W, packet_size: usize) -> Self {
+    return PacketWriter {
+      writer: BufWriter::new(inner),
+      packet_size,
+      pending: Vec::new(),
+      arrival_clock: None,
+      step: 0,
+    }
+  }
+
+  fn write_timestamped(&mut self, arrival_clock: u64, packet: &super::packet::Packet) -> std::io::Result<()> {
+    let ats = (arrival_clock % super::packet::ARRIVAL_TIMESTAMP_CLOCKS) as u32;
+    self.writer.write_all(&ats.to_be_bytes())?;
+    self.writer.write_all(packet)?;
+    return Ok(());
+  }
+
+  // where the arrival clock (27MHz) is after the packets written so far, the ones since it last moved are spread up to it.
+  // It only goes forward, packets are held while it does not
+  pub fn set_arrival_clock(&mut self, clock: u64) -> std::io::Result<()> {
+    let Some(begin) = self.arrival_clock else {
+      self.arrival_clock = Some(clock);
+      return Ok(());
+    };
+    if clock <= begin { return Ok(()); }
+
+    let pending = std::mem::take(&mut self.pending);
+    let count = pending.len() as u64;
+    for (index, packet) in pending.iter().enumerate() {
+      self.write_timestamped(begin + (clock - begin) * index as u64 / count, packet)?;
+    }
+    if let Some(step) = (clock - begin).checked_div(count) {
+      self.step = step;
+    }
+    self.arrival_clock = Some(clock);
+    return Ok(());
+  }
+
+  // the input arrived at `clock` (27MHz) without a packet of its own being written, as for an input packet which was rewritten or dropped.
+  // The packets written since the last arrival are spread up to it
+  pub fn arrive(&mut self, clock: u64) -> std::io::Result<()> {
+    if self.packet_size != super::packet::TIMESTAMPED_PACKET_SIZE { return Ok(()); }
+
+    // the pending packets start a step after the last packet written
+    let begin = self.arrival_clock.map_or(clock, |start| start.saturating_sub(self.step)).min(clock);
+    let pending = std::mem::take(&mut self.pending);
+    let count = pending.len() as u64;
+    for (index, pending) in pending.iter().enumerate() {
+      self.write_timestamped(begin + (clock - begin) * (index as u64 + 1) / count, pending)?;
+    }
+    if let Some(step) = (clock - begin).checked_div(count) {
+      self.step = step;
+    }
+    self.arrival_clock = Some(clock + self.step);
+    return Ok(());
+  }
+
+  // writes a packet which arrived at `clock` (27MHz) with that timestamp as it is, as for a packet passed through from the input
+  pub fn write_arrived(&mut self, packet: &super::packet::Packet, clock: u64) -> std::io::Result<()> {
+    if self.packet_size != super::packet::TIMESTAMPED_PACKET_SIZE {
+      return self.writer.write_all(packet);
+    }
+
+    self.pending.push(*packet);
+    return self.arrive(clock);
+  }
+
+  // gives the pending packets timestamps at the pace of the last spread
+  fn write_pending(&mut self) -> std::io::Result<()> {
+    let mut clock = self.arrival_clock.unwrap_or(0);
+    for packet in std::mem::take(&mut self.pending) {
+      self.write_timestamped(clock, &packet)?;
+      clock += self.step;
+    }
+    self.arrival_clock = Some(clock);
+    return Ok(());
+  }
+
+  pub fn write(&mut self, packet: &super::packet::Packet) -> std::io::Result<()> {
+    if self.packet_size != super::packet::TIMESTAMPED_PACKET_SIZE {
+      return self.writer.write_all(packet);
+    }
+
+    self.pending.push(*packet);
+    if self.pending.len() >= PENDING_LIMIT {
+      self.write_pending()?;
+    }
+    return Ok(());
+  }
+
+  pub fn flush(&mut self) -> std::io::Result<()> {
+    self.write_pending()?;
+    return self.writer.flush();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn arrival_timestamps(output: &[u8]) -> Vec<u32> {
+    return output.chunks(super::super::packet::TIMESTAMPED_PACKET_SIZE).map(|chunk| u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])).collect();
+  }
+
+  #[test]
+  fn spreads_up_to_the_arrival_clock() {
+    let packet = [0x47u8; super::super::packet::PACKET_SIZE];
+    let mut output = Vec::new();
+    {
+      let mut writer = PacketWriter::new(&mut output, super::super::packet::TIMESTAMPED_PACKET_SIZE);
+      for clock in [1000, 1000, 1000, 1600, 1500, 1900] {
+        writer.write(&packet).unwrap();
+        writer.set_arrival_clock(clock).unwrap();
+      }
+      writer.write(&packet).unwrap();
+      writer.flush().unwrap();
+    }
+
+    // held until the clock first moves, held over the step back, and the last one at the pace before it
+    assert_eq!(arrival_timestamps(&output), vec![1000, 1150, 1300, 1450, 1600, 1750, 1900]);
+  }
+
+  #[test]
+  fn wraps_around() {
+    let packet = [0x47u8; super::super::packet::PACKET_SIZE];
+    let mut output = Vec::new();
+    {
+      let mut writer = PacketWriter::new(&mut output, super::super::packet::TIMESTAMPED_PACKET_SIZE);
+      writer.set_arrival_clock(super::super::packet::ARRIVAL_TIMESTAMP_CLOCKS - 100).unwrap();
+      writer.write(&packet).unwrap();
+      writer.write(&packet).unwrap();
+      writer.set_arrival_clock(super::super::packet::ARRIVAL_TIMESTAMP_CLOCKS + 100).unwrap();
+      writer.flush().unwrap();
+    }
+
+    assert_eq!(arrival_timestamps(&output), vec![(super::super::packet::ARRIVAL_TIMESTAMP_CLOCKS - 100) as u32, 0]);
+  }
+
+  #[test]
+  fn arrived_packets_keep_their_timestamps() {
+    let packet = [0x47u8; super::super::packet::PACKET_SIZE];
+    let mut output = Vec::new();
+    {
+      let mut writer = PacketWriter::new(&mut output, super::super::packet::TIMESTAMPED_PACKET_SIZE);
+      // a rewritten input packet, generated packets between input packets, and after the last one
+      writer.write(&packet).unwrap();
+      writer.arrive(1000000).unwrap();
+      writer.write_arrived(&packet, 1005000).unwrap();
+      writer.write(&packet).unwrap();
+      writer.write(&packet).unwrap();
+      writer.write_arrived(&packet, 1020000).unwrap();
+      writer.arrive(1025000).unwrap();
+      writer.write(&packet).unwrap();
+      writer.flush().unwrap();
+    }
+
+    assert_eq!(arrival_timestamps(&output), vec![1000000, 1005000, 1010000, 1015000, 1020000, 1030000]);
+  }
+}
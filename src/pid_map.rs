@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+
+use crate::error::{Error, Result};
+
+pub const MIN_ELEMENTARY_PID: u16 = 0x0030;
+pub const MAX_ELEMENTARY_PID: u16 = 0x1FFE;
+
+// explicit output PIDs of transplanted metadata streams, by their PID in the metadata
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PidMap {
+  map: HashMap<u16, u16>,
+}
+
+fn parse_pid(text: &str) -> Option<u16> {
+  let text = text.trim();
+  let pid = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+    Some(hex) => u16::from_str_radix(hex, 16).ok()?,
+    None => text.parse::<u16>().ok()?,
+  };
+  return if pid <= MAX_ELEMENTARY_PID { Some(pid) } else { None };
+}
+
+impl PidMap {
+  // "0x130=0x0130,0x138=0x0200"
+  pub fn parse(text: &str) -> Result<Self> {
+    let mut map = HashMap::new();
+    for item in text.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+      let Some((Some(source), Some(target))) = item.split_once('=').map(|(source, target)| (parse_pid(source), parse_pid(target))) else {
+        return Err(Error::InvalidOption(format!("malformed pid mapping \"{}\"", item)));
+      };
+      if target < MIN_ELEMENTARY_PID {
+        return Err(Error::InvalidOption(format!("pid 0x{:04X} is reserved", target)));
+      }
+      if map.values().any(|mapped| *mapped == target) {
+        return Err(Error::InvalidOption(format!("pid 0x{:04X} is mapped more than once", target)));
+      }
+      if map.insert(source, target).is_some() {
+        return Err(Error::InvalidOption(format!("pid 0x{:04X} is mapped more than once", source)));
+      }
+    }
+    return Ok(PidMap { map });
+  }
+
+  pub fn get(&self, source: u16) -> Option<u16> {
+    return self.map.get(&source).copied();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn hex_and_decimal() {
+    let map = PidMap::parse("0x130=0x0130, 312=0X200,").unwrap();
+    assert_eq!(map.get(0x0130), Some(0x0130));
+    assert_eq!(map.get(0x0138), Some(0x0200));
+    assert_eq!(map.get(0x0140), None);
+    assert_eq!(PidMap::parse("").unwrap(), PidMap::default());
+  }
+
+  #[test]
+  fn malformed_mappings() {
+    for text in ["0x130", "0x130=", "=0x130", "0x130=0x2000", "0x1FFF=0x130", "0x130=x", "0x130=0x0010"] {
+      assert!(PidMap::parse(text).is_err(), "{}", text);
+    }
+  }
+
+  #[test]
+  fn mapped_once() {
+    assert!(PidMap::parse("0x130=0x200,0x138=0x200").is_err());
+    assert!(PidMap::parse("0x130=0x200,0x130=0x210").is_err());
+    // swapping two PIDs is fine
+    assert!(PidMap::parse("0x130=0x138,0x138=0x130").is_ok());
+  }
+}
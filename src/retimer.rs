@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+
+use crate::mpeg2ts;
+use mpeg2ts::packet::Packet;
+use mpeg2ts::packetize::packetize_pes;
+use mpeg2ts::continuity::ContinuityCounters;
+use mpeg2ts::reader::PesReader;
+
+pub struct PesRetimer {
+  offset: i64,
+  readers: HashMap<u16, PesReader>,
+}
+
+impl PesRetimer {
+  pub fn new(offset_ms: i64) -> Self {
+    return PesRetimer {
+      offset: offset_ms * (mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC as i64) / 1000,
+      readers: HashMap::new(),
+    }
+  }
+
+  // maps a metadata timestamp onto the input timeline, `shift` is the same mapping used for scheduling by `elapsed`
+  fn map(timestamp: u64, shift: i64) -> u64 {
+    let clocks = mpeg2ts::packet::MPEGTS_TIMESTAMP_CLOCKS as i64;
+    return ((timestamp as i64) + shift).rem_euclid(clocks) as u64;
+  }
+
+  // reassembles PES of `pid`, and returns repacketized PES with PTS/DTS rewritten once a PES completes
+  pub fn push(&mut self, packet: &Packet, shift: i64, continuity_counters: &mut ContinuityCounters) -> Vec<Packet> {
+    let shift = shift + self.offset;
+    let pid = mpeg2ts::packet::pid(packet);
+
+    let reader = self.readers.entry(pid).or_default();
+    reader.push(packet);
+
+    let mut packets = Vec::new();
+    while let Some(mut pes) = reader.pop() {
+      if let Some(pts) = mpeg2ts::pes::pts(&pes) {
+        mpeg2ts::pes::set_pts(&mut pes, Self::map(pts, shift));
+      }
+      if let Some(dts) = mpeg2ts::pes::dts(&pes) {
+        mpeg2ts::pes::set_dts(&mut pes, Self::map(dts, shift));
+      }
+
+      for mut repacketized in packetize_pes(&pes, pid, 0) {
+        continuity_counters.stamp(&mut repacketized);
+        packets.push(repacketized);
+      }
+    }
+
+    return packets;
+  }
+}
@@ -0,0 +1,223 @@
+use crate::mpeg2ts;
+use crate::error::{Error, Result};
+
+// one condition on a metadata PMT stream, every specified field has to match
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamRule {
+  pub stream_types: Vec<u8>,
+  // inclusive ranges of component_tag in the stream_identifier_descriptor
+  pub component_tags: Vec<(u8, u8)>,
+  // descriptor tags which have to be present in the ES info
+  pub descriptors: Vec<u8>,
+}
+
+// which metadata streams are transplanted, a stream is selected when any of the rules matches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamSelection {
+  rules: Vec<StreamRule>,
+}
+
+fn parse_number(text: &str) -> Option<u8> {
+  let text = text.trim();
+  return match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+    Some(hex) => u8::from_str_radix(hex, 16).ok(),
+    None => text.parse::<u8>().ok(),
+  };
+}
+
+fn parse_range(text: &str) -> Option<(u8, u8)> {
+  return match text.split_once('-') {
+    Some((first, last)) => {
+      let (first, last) = (parse_number(first)?, parse_number(last)?);
+      if first > last { None } else { Some((first, last)) }
+    },
+    None => parse_number(text).map(|tag| (tag, tag)),
+  };
+}
+
+impl StreamRule {
+  pub fn matches(&self, stream_type: u8, es_info: &[u8]) -> bool {
+    if !self.stream_types.is_empty() && !self.stream_types.contains(&stream_type) { return false; }
+
+    if !self.component_tags.is_empty() {
+      let Some(component_tag) = mpeg2ts::pmt::component_tag(es_info) else { return false; };
+      if !self.component_tags.iter().any(|(first, last)| *first <= component_tag && component_tag <= *last) { return false; }
+    }
+
+    let descriptors = mpeg2ts::pmt::descriptors(es_info);
+    return self.descriptors.iter().all(|required| descriptors.iter().any(|(tag, _)| tag == required));
+  }
+
+  // "type=0x06+tag=0x30-0x37+descriptor=0xFD", fields may be repeated
+  fn parse(text: &str) -> Option<Self> {
+    let mut rule = StreamRule::default();
+    for field in text.split('+') {
+      let (key, value) = field.split_once('=')?;
+      match key.trim() {
+        "type" => rule.stream_types.push(parse_number(value)?),
+        "tag" => rule.component_tags.push(parse_range(value)?),
+        "descriptor" => rule.descriptors.push(parse_number(value)?),
+        _ => { return None; }
+      }
+    }
+    return Some(rule);
+  }
+}
+
+impl StreamSelection {
+  pub fn new(rules: Vec<StreamRule>) -> Self {
+    return StreamSelection { rules };
+  }
+
+  pub fn preset(name: &str) -> Option<Vec<StreamRule>> {
+    return match name {
+      "captions" => Some(vec![StreamRule { stream_types: vec![0x06], component_tags: vec![(0x30, 0x37)], ..Default::default() }]),
+      "superimpose" => Some(vec![StreamRule { stream_types: vec![0x06], component_tags: vec![(0x38, 0x38)], ..Default::default() }]),
+      "data-broadcasting" => Some(vec![StreamRule { stream_types: vec![0x0D], ..Default::default() }]),
+      "private-data" => Some(vec![StreamRule { stream_types: vec![0x05, 0x06, 0x0D], ..Default::default() }]),
+      _ => None,
+    };
+  }
+
+  // comma separated presets and rules, e.g. "captions,superimpose" or "data-broadcasting,type=0x06+tag=0x30"
+  pub fn parse(text: &str) -> Result<Self> {
+    let mut rules = Vec::new();
+    for item in text.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+      match StreamSelection::preset(item).or_else(|| StreamRule::parse(item).map(|rule| vec![rule])) {
+        Some(preset) => rules.extend(preset),
+        None => {
+          return Err(Error::InvalidOption(format!("unknown stream selection \"{}\"", item)));
+        }
+      }
+    }
+    if rules.is_empty() {
+      return Err(Error::InvalidOption("stream selection must not be empty".to_string()));
+    }
+    return Ok(StreamSelection { rules });
+  }
+
+  pub fn matches(&self, stream_type: u8, es_info: &[u8]) -> bool {
+    return self.rules.iter().any(|rule| rule.matches(stream_type, es_info));
+  }
+}
+
+impl Default for StreamSelection {
+  // superimpose and data broadcasting
+  fn default() -> Self {
+    let mut rules = StreamSelection::preset("superimpose").unwrap_or_default();
+    rules.extend(StreamSelection::preset("data-broadcasting").unwrap_or_default());
+    return StreamSelection { rules };
+  }
+}
+
+// which program_info descriptors of the metadata PMT are carried into the output PMT
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DescriptorFilter {
+  #[default]
+  None,
+  All,
+  Keep(Vec<u8>),
+  Drop(Vec<u8>),
+}
+
+impl DescriptorFilter {
+  // "all", "keep:0xC1,0xDE" or "drop:0x09"
+  pub fn parse(text: &str) -> Result<Self> {
+    let text = text.trim();
+    if text == "all" { return Ok(DescriptorFilter::All); }
+    if text == "none" { return Ok(DescriptorFilter::None); }
+
+    let (kind, tags) = match (text.strip_prefix("keep:"), text.strip_prefix("drop:")) {
+      (Some(tags), _) => (true, tags),
+      (_, Some(tags)) => (false, tags),
+      _ => {
+        return Err(Error::InvalidOption(format!("descriptor filter must be all, none, keep:TAGS or drop:TAGS: \"{}\"", text)));
+      }
+    };
+    let mut parsed = Vec::new();
+    for tag in tags.split(',').filter(|tag| !tag.trim().is_empty()) {
+      let Some(tag) = parse_number(tag) else {
+        return Err(Error::InvalidOption(format!("malformed descriptor tag \"{}\"", tag)));
+      };
+      parsed.push(tag);
+    }
+    return Ok(if kind { DescriptorFilter::Keep(parsed) } else { DescriptorFilter::Drop(parsed) });
+  }
+
+  pub fn matches(&self, tag: u8) -> bool {
+    return match self {
+      DescriptorFilter::None => false,
+      DescriptorFilter::All => true,
+      DescriptorFilter::Keep(tags) => tags.contains(&tag),
+      DescriptorFilter::Drop(tags) => !tags.contains(&tag),
+    };
+  }
+
+  // input descriptors with the filtered metadata ones, which replace input descriptors of the same tag
+  pub fn merge(&self, input: &[u8], metadata: &[u8]) -> Vec<u8> {
+    let carried = mpeg2ts::pmt::descriptors(metadata).into_iter().filter(|(tag, _)| self.matches(*tag)).collect::<Vec<_>>();
+
+    let mut merged = Vec::new();
+    for (tag, body) in mpeg2ts::pmt::descriptors(input) {
+      if carried.iter().any(|(carried_tag, _)| *carried_tag == tag) { continue; }
+      merged.push(tag);
+      merged.push(body.len() as u8);
+      merged.extend(body);
+    }
+    for (tag, body) in carried {
+      merged.push(tag);
+      merged.push(body.len() as u8);
+      merged.extend(body);
+    }
+    return merged;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // stream_identifier_descriptor with the component_tag, and a data_component_descriptor
+  const CAPTION: [u8; 7] = [0x52, 0x01, 0x30, 0xFD, 0x02, 0x00, 0x08];
+  const SUPERIMPOSE: [u8; 3] = [0x52, 0x01, 0x38];
+
+  #[test]
+  fn presets_and_rules() {
+    let selection = StreamSelection::parse("captions, type=0x0D").unwrap();
+    assert!(selection.matches(0x06, &CAPTION));
+    assert!(!selection.matches(0x06, &SUPERIMPOSE));
+    assert!(!selection.matches(0x06, &[]));
+    assert!(selection.matches(0x0D, &[]));
+
+    let selection = StreamSelection::parse("type=0x06+tag=48-0x37+descriptor=0xFD").unwrap();
+    assert!(selection.matches(0x06, &CAPTION));
+    assert!(!selection.matches(0x06, &CAPTION[..3]));
+    assert!(!selection.matches(0x0D, &CAPTION));
+
+    let selection = StreamSelection::default();
+    assert!(selection.matches(0x06, &SUPERIMPOSE));
+    assert!(selection.matches(0x0D, &[]));
+    assert!(!selection.matches(0x06, &CAPTION));
+  }
+
+  #[test]
+  fn malformed_selection() {
+    for text in ["", " , ", "subtitles", "type", "type=0x100", "tag=0x37-0x30", "kind=0x06", "type=0x06+"] {
+      assert!(StreamSelection::parse(text).is_err(), "{}", text);
+    }
+  }
+
+  #[test]
+  fn descriptor_filter() {
+    assert_eq!(DescriptorFilter::parse("keep:0xC1,222").unwrap(), DescriptorFilter::Keep(vec![0xC1, 0xDE]));
+    assert_eq!(DescriptorFilter::parse("drop:0x09").unwrap(), DescriptorFilter::Drop(vec![0x09]));
+    assert!(DescriptorFilter::parse("keep").is_err());
+    assert!(DescriptorFilter::parse("drop:0x09,x").is_err());
+
+    // metadata descriptors replace input ones of the same tag and go after the rest
+    let input = [0x09, 0x01, 0xAA, 0xC1, 0x01, 0x00];
+    let metadata = [0xC1, 0x01, 0x84, 0xDE, 0x00];
+    assert_eq!(DescriptorFilter::Keep(vec![0xC1]).merge(&input, &metadata), vec![0x09, 0x01, 0xAA, 0xC1, 0x01, 0x84]);
+    assert_eq!(DescriptorFilter::None.merge(&input, &metadata), input.to_vec());
+  }
+}
@@ -0,0 +1,170 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::mpeg2ts;
+use mpeg2ts::packet::Packet;
+use mpeg2ts::reader::SectionReader;
+use mpeg2ts::section::Section;
+use crate::error::{Error, Result};
+
+// SI tables by name, and the PIDs they are carried on
+pub const SI_TABLES: [(&str, &[u16]); 9] = [
+  ("nit", &[0x10]),
+  ("sdt", &[0x11]),
+  ("eit", &[0x12, 0x26, 0x27]),
+  ("tot", &[0x14]),
+  ("sdtt", &[0x23, 0x28]),
+  ("bit", &[0x24]),
+  ("cdt", &[0x29]),
+  // partial TS (recorded services) carry SIT instead of NIT/SDT/EIT, and DIT where the recording is discontinuous
+  ("sit", &[0x1F]),
+  ("dit", &[0x1E]),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SiPolicy {
+  // taken from the metadata, the input's is removed
+  Metadata,
+  // kept from the input, the metadata's is not taken
+  Input,
+  // removed from both
+  Drop,
+  // sections of both, the metadata's taking precedence for the same table
+  Merge,
+}
+
+// what happens to each SI PID, PIDs without a policy are kept from the input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SiPolicies {
+  policies: HashMap<u16, SiPolicy>,
+}
+
+impl SiPolicies {
+  pub fn set(&mut self, table: &str, policy: SiPolicy) -> Result<()> {
+    let Some((_, pids)) = SI_TABLES.iter().find(|(name, _)| *name == table) else {
+      return Err(Error::InvalidOption(format!("unknown SI table \"{}\"", table)));
+    };
+    for pid in pids.iter() {
+      self.policies.insert(*pid, policy);
+    }
+    return Ok(());
+  }
+
+  // comma separated "table=policy" over the defaults, e.g. "sdt=input,eit=merge,cdt=drop"
+  pub fn parse(text: &str) -> Result<Self> {
+    let mut policies = SiPolicies::default();
+    for item in text.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+      let Some((table, policy)) = item.split_once('=') else {
+        return Err(Error::InvalidOption(format!("malformed SI policy \"{}\"", item)));
+      };
+      let policy = match policy.trim() {
+        "metadata" => SiPolicy::Metadata,
+        "input" => SiPolicy::Input,
+        "drop" => SiPolicy::Drop,
+        "merge" => SiPolicy::Merge,
+        _ => {
+          return Err(Error::InvalidOption(format!("SI policy must be metadata, input, drop or merge: \"{}\"", item)));
+        }
+      };
+      policies.set(&table.trim().to_ascii_lowercase(), policy)?;
+    }
+    return Ok(policies);
+  }
+
+  pub fn policy(&self, pid: u16) -> SiPolicy {
+    return self.policies.get(&pid).copied().unwrap_or(SiPolicy::Input);
+  }
+
+  // PIDs to take from the metadata
+  pub fn metadata_pids(&self) -> HashSet<u16> {
+    return self.policies.iter().filter(|(_, policy)| matches!(policy, SiPolicy::Metadata | SiPolicy::Merge)).map(|(pid, _)| *pid).collect();
+  }
+}
+
+impl Default for SiPolicies {
+  // NIT, SDT, EIT, TOT, BIT and SIT/DIT of partial TS from the metadata, the others from the input
+  fn default() -> Self {
+    let mut policies = SiPolicies { policies: HashMap::new() };
+    for table in ["nit", "sdt", "eit", "tot", "bit", "sit", "dit"] {
+      let _ = policies.set(table, SiPolicy::Metadata);
+    }
+    return policies;
+  }
+}
+
+// reassembles sections of the merged PIDs from both streams
+#[derive(Default)]
+pub struct SiMerger {
+  input: HashMap<u16, SectionReader>,
+  metadata: HashMap<u16, SectionReader>,
+  // (pid, table_id, table_id_extension, section_number) which the metadata carries
+  metadata_tables: HashSet<(u16, u8, u16, u8)>,
+}
+
+fn table_key(pid: u16, section: &Section) -> (u16, u8, u16, u8) {
+  if !mpeg2ts::section::has_extended_header(section) {
+    return (pid, mpeg2ts::section::table_id(section), 0, 0);
+  }
+  return (pid, mpeg2ts::section::table_id(section), mpeg2ts::section::table_id_extension(section), mpeg2ts::section::section_number(section));
+}
+
+impl SiMerger {
+  pub fn new() -> Self {
+    return SiMerger::default();
+  }
+
+  // completed input sections, without the tables which the metadata carries
+  pub fn push_input(&mut self, packet: &Packet) -> Vec<Section> {
+    let pid = mpeg2ts::packet::pid(packet);
+    let reader = self.input.entry(pid).or_default();
+    reader.push(packet);
+
+    let mut sections = Vec::new();
+    while let Some(section) = reader.pop() {
+      if self.metadata_tables.contains(&table_key(pid, &section)) { continue; }
+      sections.push(section);
+    }
+    return sections;
+  }
+
+  pub fn push_metadata(&mut self, packet: &Packet) -> Vec<Section> {
+    let pid = mpeg2ts::packet::pid(packet);
+    let reader = self.metadata.entry(pid).or_default();
+    reader.push(packet);
+
+    let mut sections = Vec::new();
+    while let Some(section) = reader.pop() {
+      self.metadata_tables.insert(table_key(pid, &section));
+      sections.push(section);
+    }
+    return sections;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn policies_over_the_defaults() {
+    let policies = SiPolicies::parse(" SDT=input, eit=merge,cdt=drop,").unwrap();
+    assert_eq!(policies.policy(0x10), SiPolicy::Metadata);
+    assert_eq!(policies.policy(0x11), SiPolicy::Input);
+    for pid in [0x12, 0x26, 0x27] {
+      assert_eq!(policies.policy(pid), SiPolicy::Merge);
+    }
+    assert_eq!(policies.policy(0x29), SiPolicy::Drop);
+    assert_eq!(policies.policy(0x23), SiPolicy::Input);
+
+    let mut pids = policies.metadata_pids().into_iter().collect::<Vec<_>>();
+    pids.sort();
+    assert_eq!(pids, vec![0x10, 0x12, 0x14, 0x1E, 0x1F, 0x24, 0x26, 0x27]);
+    assert_eq!(SiPolicies::parse("").unwrap(), SiPolicies::default());
+  }
+
+  #[test]
+  fn malformed_policies() {
+    for text in ["sdt", "sdt=", "sdt=keep", "pmt=input", "=input"] {
+      assert!(SiPolicies::parse(text).is_err(), "{}", text);
+    }
+  }
+}
@@ -0,0 +1,854 @@
+use std::io::Read;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::mpeg2ts;
+use mpeg2ts::packet::Packet;
+use mpeg2ts::packetize::packtize_section;
+use mpeg2ts::continuity::ContinuityCounters;
+use mpeg2ts::reader::{SectionEvent, SectionReader};
+use crate::clock::{Clock, ClockSource, Discontinuity, Recovery};
+use crate::metadata::{self, MetadataSource};
+use crate::retimer::PesRetimer;
+use crate::pid_map::{self, PidMap};
+use crate::si::{SiMerger, SiPolicies, SiPolicy};
+use crate::selection::DescriptorFilter;
+use crate::error::{Error, Result};
+
+// repetition interval of the output PAT in 90kHz
+pub const PAT_INTERVAL: u64 = mpeg2ts::packet::MPEGTS_TIMESTAMP_1SEC / 10;
+// packets between PATs at most, for while the clock does not advance (before the first PCR, or without one)
+pub const PAT_PACKET_INTERVAL: u64 = 2000;
+
+// component_tag given to input video/audio streams which have no stream_identifier_descriptor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ComponentTags {
+  #[default]
+  None,
+  // 0x00 onwards for video and 0x10 onwards for audio
+  Default,
+  // the tag of the metadata stream of the same kind in the same order, or the default one
+  Source,
+}
+
+// what happens to ARIB caption/superimpose/data broadcasting streams which the input PMT already has,
+// with the same stream_type as a transplanted metadata stream and the same component_tag when both have one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExistingStreams {
+  // dropped from the input, the metadata streams take their place
+  #[default]
+  Replace,
+  // kept from the input, the metadata streams are not transplanted
+  Keep,
+  // both are output, the metadata streams moved to other PIDs where they collide
+  Both,
+}
+
+// component_tag of ARIB captions (0x30-0x37), superimpose (0x38-0x3F) and data broadcasting (0x40-0x7F)
+const ARIB_DATA_COMPONENT_TAGS: std::ops::RangeInclusive<u8> = 0x30..=0x7F;
+
+// whether an input private data stream is ARIB content which the metadata may carry as well, told by its component_tag
+// or a data_component_descriptor. Other private data (AC-3, DVB subtitles, ...) on 0x06 never is
+fn is_arib_data_component(es_info: &[u8]) -> bool {
+  return match mpeg2ts::pmt::component_tag(es_info) {
+    Some(tag) => ARIB_DATA_COMPONENT_TAGS.contains(&tag),
+    None => mpeg2ts::pmt::descriptors(es_info).iter().any(|(tag, _)| *tag == 0xFD),
+  };
+}
+
+// the metadata stream (source PID) an input stream of the same kind already carries, among those not taken yet.
+// stream_type tells it, component_tag only chooses among several and a missing one matches any
+fn existing_source(components: &[(u16, u8, Option<u8>)], taken: &HashSet<u16>, stream_type: u8, es_info: &[u8]) -> Option<u16> {
+  if !mpeg2ts::pmt::is_data_stream_type(stream_type) || !is_arib_data_component(es_info) { return None; }
+
+  let component_tag = mpeg2ts::pmt::component_tag(es_info);
+  let candidates = components.iter().filter(|(source_pid, source_type, _)| *source_type == stream_type && !taken.contains(source_pid));
+  let found = candidates.clone().find(|(_, _, tag)| tag.is_some() && *tag == component_tag)
+    .or_else(|| candidates.clone().find(|(_, _, tag)| tag.is_none() || component_tag.is_none()));
+  return found.map(|(source_pid, _, _)| *source_pid);
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TransplantOptions {
+  // align timelines by absolute PCR/PTS values, for encodes which kept source timestamps
+  pub align: bool,
+  // rewrite PTS/DTS of transplanted caption/superimpose PES, with an additional offset in milliseconds
+  pub rewrite_pes: bool,
+  pub pes_offset: i64,
+  // explicit output PIDs of metadata streams, the others keep their PID unless it collides with the input
+  pub pid_map: PidMap,
+  // what happens to each SI table of the input and the metadata
+  pub si_policies: SiPolicies,
+  // program_info descriptors of the metadata PMT carried into the output PMT
+  pub program_descriptors: DescriptorFilter,
+  pub component_tags: ComponentTags,
+  pub existing_streams: ExistingStreams,
+  // how the timelines of the input and the metadata continue over a PCR discontinuity
+  pub pcr_recovery: Recovery,
+  // what the timelines follow, PCR or PTS
+  pub clock_source: ClockSource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+  Input,
+  Metadata,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+  // input start on the metadata timeline in 90kHz, and the difference of PTS lead over PCR when it could be compared
+  Aligned { program_number: u16, position: i64, lead_difference: Option<i64> },
+  // a metadata stream was moved to another PID, as its PID (or the one it was mapped to) is used by the input
+  PidRemapped { source: u16, requested: u16, pid: u16 },
+  // the transplanted streams changed with the metadata PMT, now being output on `pids`
+  StreamsChanged { program_number: u16, pids: Vec<u16> },
+  // the input PMT already has a private data stream which the metadata also carries, handled as `policy`
+  ExistingStream { program_number: u16, pid: u16, stream_type: u8, component_tag: Option<u8>, policy: ExistingStreams },
+  // PCR of the input or the metadata jumped, the timeline was continued as the recovery option says
+  PcrDiscontinuity { source: Source, discontinuity: Discontinuity },
+  // no PCR came on `pcr_pid` for a while, the timeline follows PTS of `pid` from now on
+  PtsFallback { source: Source, pcr_pid: Option<u16>, pid: u16 },
+  // a PAT/PMT section was dropped for its CRC or packet loss, or changed its version
+  Section { source: Source, event: SectionEvent },
+}
+
+// one input program and the metadata service transplanted into it
+struct Program<R> {
+  meta: MetadataSource<R>,
+  pes_retimer: Option<PesRetimer>,
+
+  // program of the input, the first one in the PAT when None
+  program_number: Option<u16>,
+  pmt_program_number: u16,
+
+  pmt_reader: SectionReader,
+  // the last input PMT, rewritten again when the metadata PMT changes,
+  // and the output PMT (without version_number and CRC) with its version_number
+  input_pmt: Option<mpeg2ts::section::Section>,
+  pmt: Option<Vec<u8>>,
+  pmt_version: Option<u8>,
+
+  pmt_pid: Option<u16>,
+
+  // output PIDs of metadata streams by their metadata PID, decided once the input PMT is seen
+  output_pids: HashMap<u16, u16>,
+  // private data streams of the input PMT which the metadata also carries, and those of them which are dropped
+  existing_pids: HashSet<u16>,
+  dropped_pids: HashSet<u16>,
+  // metadata streams which are not transplanted, as the input already has them, and those which had packets left out for it
+  skipped_pids: HashSet<u16>,
+  left_out_pids: HashSet<u16>,
+
+  clock: Clock,
+
+  // position of the input start on the metadata timeline, measured from the first PCR of each
+  start_position: Option<i64>,
+}
+
+pub struct Transplanter<R> {
+  programs: Vec<Program<R>>,
+
+  pid_map: PidMap,
+  input_pids: HashSet<u16>,
+
+  si_policies: SiPolicies,
+  si_merger: SiMerger,
+  program_descriptors: DescriptorFilter,
+  component_tags: ComponentTags,
+  existing_streams: ExistingStreams,
+
+  pat_transport_stream_id: u16,
+  pat_reader: SectionReader,
+
+  // output PAT state: programs in order, the input's network_PID, and the current PAT with when it was last output
+  pat_programs: Vec<(u16, u16)>,
+  input_network_pid: Option<u16>,
+  pat_version: Option<u8>,
+  pat: Option<mpeg2ts::section::Section>,
+  pat_elapsed: Option<u64>,
+  pat_packets: u64,
+  // the PAT was repeated on its own since the last input PAT
+  pat_repeated: bool,
+
+  // every packet which is not passed through from the input is renumbered here
+  continuity_counters: ContinuityCounters,
+  // where the input packet is among the packets of the last push, when it was passed through as it is
+  passed_through: Option<usize>,
+
+  events: VecDeque<Event>,
+}
+
+impl<R: Read> Transplanter<R> {
+  // transplants into the input program of `program_number`, the first one in the PAT when None
+  pub fn new(meta: MetadataSource<R>, program_number: Option<u16>, options: TransplantOptions) -> Result<Self> {
+    return Self::with_programs(vec![(program_number, meta)], options);
+  }
+
+  // pairs each input program (by program_number) with the metadata service to transplant into it.
+  // SI is carried once for the multiplex, from the first metadata
+  pub fn with_programs(pairs: Vec<(Option<u16>, MetadataSource<R>)>, options: TransplantOptions) -> Result<Self> {
+    if pairs.is_empty() {
+      return Err(Error::InvalidOption("no program to transplant".to_string()));
+    }
+    if pairs.len() > 1 && pairs.iter().any(|(program_number, _)| program_number.is_none()) {
+      return Err(Error::InvalidOption("program number must be given for every program of a multi program output".to_string()));
+    }
+
+    let mut programs: Vec<Program<R>> = Vec::new();
+    for (index, (program_number, mut meta)) in pairs.into_iter().enumerate() {
+      if index > 0 {
+        meta.set_si_pids(HashSet::new());
+      }
+      meta.set_clock_options(options.pcr_recovery, options.clock_source);
+      meta.prime()?;
+      if options.align {
+        meta.prime_alignment()?;
+      }
+
+      let Some(pmt_program_number) = meta.pmt_program_number() else {
+        return Err(Error::MissingPat);
+      };
+      if programs.iter().any(|program| program.pmt_program_number == pmt_program_number) {
+        return Err(Error::InvalidOption(format!("service {} is paired with more than one program", pmt_program_number)));
+      }
+      if program_number.is_some() && programs.iter().any(|program| program.program_number == program_number) {
+        return Err(Error::InvalidOption(format!("program {} is paired with more than one service", program_number.unwrap_or(0))));
+      }
+
+      let mut pmt_reader = SectionReader::new();
+      pmt_reader.set_well_formed(mpeg2ts::pmt::is_well_formed);
+      programs.push(Program {
+        meta,
+        pes_retimer: if options.rewrite_pes { Some(PesRetimer::new(options.pes_offset)) } else { None },
+        program_number,
+        pmt_program_number,
+        pmt_reader,
+        input_pmt: None,
+        pmt: None,
+        pmt_version: None,
+        pmt_pid: None,
+        output_pids: HashMap::new(),
+        existing_pids: HashSet::new(),
+        dropped_pids: HashSet::new(),
+        skipped_pids: HashSet::new(),
+        left_out_pids: HashSet::new(),
+        clock: Clock::new(options.pcr_recovery, options.clock_source),
+        start_position: if options.align { None } else { Some(0) },
+      });
+    }
+
+    let Some(pat_transport_stream_id) = programs[0].meta.pat_transport_stream_id() else {
+      return Err(Error::MissingPat);
+    };
+
+    let mut pat_reader = SectionReader::new();
+    pat_reader.set_well_formed(mpeg2ts::pat::is_well_formed);
+
+    return Ok(Transplanter {
+      programs,
+      pid_map: options.pid_map,
+      input_pids: HashSet::new(),
+      si_policies: options.si_policies,
+      si_merger: SiMerger::new(),
+      program_descriptors: options.program_descriptors,
+      component_tags: options.component_tags,
+      existing_streams: options.existing_streams,
+      pat_transport_stream_id,
+      pat_reader,
+      pat_programs: Vec::new(),
+      input_network_pid: None,
+      pat_version: None,
+      pat: None,
+      pat_elapsed: None,
+      pat_packets: 0,
+      pat_repeated: false,
+      continuity_counters: ContinuityCounters::new(),
+      passed_through: None,
+      events: VecDeque::new(),
+    })
+  }
+
+  // metadata of the first program, which also carries SI
+  pub fn metadata(&self) -> &MetadataSource<R> {
+    return &self.programs[0].meta;
+  }
+
+  pub fn metadata_mut(&mut self) -> &mut MetadataSource<R> {
+    return &mut self.programs[0].meta;
+  }
+
+  // PCR PID of the first program
+  pub fn pcr_pid(&self) -> Option<u16> {
+    return self.programs[0].clock.pcr_pid();
+  }
+
+  // programs are indexed in the order they were paired
+  pub fn program_count(&self) -> usize {
+    return self.programs.len();
+  }
+
+  // program_number of the program in the output, which is the service_id of its metadata
+  pub fn program_number(&self, index: usize) -> u16 {
+    return self.programs[index].pmt_program_number;
+  }
+
+  pub fn program_metadata(&self, index: usize) -> &MetadataSource<R> {
+    return &self.programs[index].meta;
+  }
+
+  pub fn program_metadata_mut(&mut self, index: usize) -> &mut MetadataSource<R> {
+    return &mut self.programs[index].meta;
+  }
+
+  // the input timeline of the program
+  pub fn program_clock(&self, index: usize) -> &Clock {
+    return &self.programs[index].clock;
+  }
+
+  pub fn pop_event(&mut self) -> Option<Event> {
+    return self.events.pop_front();
+  }
+
+  // index of the input packet among the packets the last push returned, when it was passed through as it is.
+  // The others were generated or taken from the metadata
+  pub fn passed_through(&self) -> Option<usize> {
+    return self.passed_through;
+  }
+
+  fn is_replaced(&self, pid: u16) -> bool {
+    return self.programs.iter().any(|program| program.dropped_pids.contains(&pid) || program.output_pids.values().any(|output_pid| *output_pid == pid));
+  }
+
+  fn output_section(&mut self, section: &mpeg2ts::section::Section, pid: u16, output: &mut Vec<Packet>) {
+    for mut packet in packtize_section(section, pid, 0) {
+      self.continuity_counters.stamp(&mut packet);
+      output.push(packet);
+    }
+  }
+
+  // feeds one input packet, and returns the packets to output in order
+  pub fn push(&mut self, packet: &Packet) -> Result<Vec<Packet>> {
+    let mut output = Vec::new();
+    self.passed_through = None;
+    let pid = mpeg2ts::packet::pid(packet);
+    if !self.programs.iter().any(|program| program.dropped_pids.contains(&pid)) {
+      self.input_pids.insert(pid);
+    }
+
+    if pid == 0 {
+      self.pat_reader.push(packet);
+      while let Some(pat) = self.pat_reader.pop() {
+        self.rewrite_pat(&pat, &mut output)?;
+      }
+    } else if let Some(index) = self.programs.iter().position(|program| program.pmt_pid == Some(pid)) {
+      self.programs[index].pmt_reader.push(packet);
+      while let Some(pmt) = self.programs[index].pmt_reader.pop() {
+        self.rewrite_pmt(index, &pmt, pid, &mut output)?;
+      }
+    } else {
+      match self.si_policies.policy(pid) {
+        SiPolicy::Metadata | SiPolicy::Drop => {},
+        SiPolicy::Merge => {
+          for section in self.si_merger.push_input(packet) {
+            self.output_section(&section, pid, &mut output);
+          }
+        },
+        SiPolicy::Input => {
+          if !self.is_replaced(pid) {
+            self.passed_through = Some(output.len());
+            output.push(*packet);
+          }
+        },
+      }
+    }
+
+    // metadata scheduled up to this packet's position follows it
+    for index in 0..self.programs.len() {
+      self.programs[index].clock.push(packet);
+      if self.programs[index].start_position.is_none() {
+        self.align(index)?;
+      }
+      self.flush_metadata(index, &mut output)?;
+    }
+
+    // the PAT goes out with each input PAT, and at its own interval when the input's is sparse
+    self.pat_packets += 1;
+    if self.pat_elapsed.is_some_and(|elapsed| self.programs[0].clock.position() >= elapsed + PAT_INTERVAL) || (self.pat.is_some() && self.pat_packets >= PAT_PACKET_INTERVAL) {
+      self.repeat_pat(&mut output);
+      self.pat_repeated = true;
+    }
+
+    while let Some(event) = self.pat_reader.pop_event() {
+      self.events.push_back(Event::Section { source: Source::Input, event });
+    }
+    for program in &mut self.programs {
+      while let Some(event) = program.pmt_reader.pop_event() {
+        self.events.push_back(Event::Section { source: Source::Input, event });
+      }
+      while let Some(event) = program.meta.pop_section_event() {
+        self.events.push_back(Event::Section { source: Source::Metadata, event });
+      }
+      if let Some(pid) = program.clock.take_fallback() {
+        self.events.push_back(Event::PtsFallback { source: Source::Input, pcr_pid: program.clock.pcr_pid(), pid });
+      }
+      if let Some(pid) = program.meta.take_clock_fallback() {
+        self.events.push_back(Event::PtsFallback { source: Source::Metadata, pcr_pid: program.meta.clock().pcr_pid(), pid });
+      }
+      while let Some(discontinuity) = program.clock.pop_discontinuity() {
+        self.events.push_back(Event::PcrDiscontinuity { source: Source::Input, discontinuity });
+      }
+      while let Some(discontinuity) = program.meta.pop_discontinuity() {
+        self.events.push_back(Event::PcrDiscontinuity { source: Source::Metadata, discontinuity });
+      }
+    }
+
+    return Ok(output);
+  }
+
+  // checks the input had what the rewrite needs, after the last packet has been pushed
+  pub fn finish(&self) -> Result<()> {
+    for program in &self.programs {
+      if program.pmt_pid.is_none() {
+        if self.pat_reader.statistics().crc_errors > 0 { return Err(Error::Crc { pid: 0 }); }
+        if self.pat_reader.statistics().malformed > 0 { return Err(Error::MalformedSection { pid: 0 }); }
+        return Err(Error::MissingPat);
+      }
+      if program.clock.pcr_pid().is_none() {
+        if let (Some(pid), true) = (program.pmt_pid, program.pmt_reader.statistics().crc_errors > 0) { return Err(Error::Crc { pid }); }
+        if let (Some(pid), true) = (program.pmt_pid, program.pmt_reader.statistics().malformed > 0) { return Err(Error::MalformedSection { pid }); }
+        return Err(Error::MissingPmt);
+      }
+    }
+    return Ok(());
+  }
+
+  fn rewrite_pat(&mut self, pat: &mpeg2ts::section::Section, output: &mut Vec<Packet>) -> Result<()> {
+    // the reader already dropped and counted broken ones
+    if mpeg2ts::section::validate(pat, 0).is_err() || !mpeg2ts::pat::is_well_formed(pat) { return Ok(()); }
+
+    let programs = mpeg2ts::pat::programs(pat);
+    let mut entries = Vec::new();
+    for program in &mut self.programs {
+      let found = match program.program_number {
+        Some(program_number) => programs.iter().position(|(number, _)| *number == program_number),
+        None => if programs.is_empty() { None } else { Some(0) },
+      };
+      if let (None, Some(program_number)) = (found, program.program_number) {
+        return Err(Error::MissingProgram { target: "input ts file", program_number, available: programs.iter().map(|(number, _)| *number).collect() });
+      }
+      program.pmt_pid = found.map(|index| programs[index].1);
+
+      if let (Some(index), Some(pmt_pid)) = (found, program.pmt_pid) {
+        entries.push((index, program.pmt_program_number, pmt_pid));
+      }
+    }
+    // the other programs pass through as they are, so they stay listed unless their program_number was taken
+    for (index, (program_number, pmt_pid)) in programs.iter().enumerate() {
+      if entries.iter().any(|(found, number, _)| *found == index || number == program_number) { continue; }
+      entries.push((index, *program_number, *pmt_pid));
+    }
+    // programs are listed in the order of the input PAT
+    entries.sort();
+
+    self.pat_programs = entries.into_iter().map(|(_, program_number, pmt_pid)| (program_number, pmt_pid)).collect();
+    self.input_network_pid = mpeg2ts::pat::network_pid(pat);
+    self.pat_version.get_or_insert(mpeg2ts::section::version_number(pat));
+
+    self.output_pat(output);
+    return Ok(());
+  }
+
+  // rebuilds the PAT of what is actually output and outputs it, bumping version_number when its contents changed
+  fn output_pat(&mut self, output: &mut Vec<Packet>) {
+    if self.pat_programs.is_empty() { return; }
+
+    // the NIT entry only when a NIT is output, partial TS metadata has none
+    let has_nit = match self.si_policies.policy(0x10) {
+      SiPolicy::Metadata => self.programs[0].meta.pat_network_pid().is_some(),
+      SiPolicy::Input => self.input_network_pid.is_some(),
+      SiPolicy::Merge => self.programs[0].meta.pat_network_pid().is_some() || self.input_network_pid.is_some(),
+      SiPolicy::Drop => false,
+    };
+    let network_pid = if has_nit { Some(0x10) } else { None };
+
+    let version = self.pat_version.unwrap_or(0);
+    let mut new_pat = mpeg2ts::pat::build(self.pat_transport_stream_id, version, network_pid, &self.pat_programs);
+    let changed = self.pat.as_ref() != Some(&new_pat);
+    // an unchanged PAT already went out in between, the input's does not need to follow it so closely
+    if std::mem::take(&mut self.pat_repeated) && !changed { return; }
+    if self.pat.is_some() && changed {
+      self.pat_version = Some((version + 1) & 0x1F);
+      new_pat = mpeg2ts::pat::build(self.pat_transport_stream_id, (version + 1) & 0x1F, network_pid, &self.pat_programs);
+    }
+
+    self.pat = Some(new_pat);
+    self.repeat_pat(output);
+  }
+
+  fn repeat_pat(&mut self, output: &mut Vec<Packet>) {
+    let Some(pat) = &self.pat else { return; };
+    for mut packet in packtize_section(pat, 0, 0) {
+      self.continuity_counters.stamp(&mut packet);
+      output.push(packet);
+    }
+    self.pat_elapsed = Some(self.programs[0].clock.position());
+    self.pat_packets = 0;
+  }
+
+  fn rewrite_pmt(&mut self, index: usize, pmt: &mpeg2ts::section::Section, pid: u16, output: &mut Vec<Packet>) -> Result<()> {
+    if mpeg2ts::section::validate(pmt, pid).is_err() || !mpeg2ts::pmt::is_well_formed(pmt) { return Ok(()); }
+
+    let program = &mut self.programs[index];
+    program.input_pmt = Some(pmt.clone());
+    program.clock.set_pcr_pid((((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 0] & 0x1F) as u16) << 8) | ((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 1] as u16) << 0));
+
+    let mut new_pmt: Vec<u8> = Vec::new();
+
+    let program_info_length = ((((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 2] & 0x0F) as u16) << 8) | ((pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 3] as u16) << 0)) as usize;
+    let mut begin = mpeg2ts::section::EXTENDED_HEADER_SIZE + 4 + program_info_length;
+    new_pmt.extend(pmt[0..mpeg2ts::section::EXTENDED_HEADER_SIZE + 4].iter());
+    new_pmt[3] = ((program.pmt_program_number & 0xFF00) >> 8) as u8;
+    new_pmt[4] = ((program.pmt_program_number & 0x00FF) >> 0) as u8;
+
+    let program_info = match self.program_descriptors {
+      DescriptorFilter::None => pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 4 .. begin].to_vec(),
+      _ => self.program_descriptors.merge(&pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 4 .. begin], program.meta.program_info()),
+    };
+    new_pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 2] = (new_pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 2] & 0xF0) | ((program_info.len() & 0x0F00) >> 8) as u8;
+    new_pmt[mpeg2ts::section::EXTENDED_HEADER_SIZE + 3] = ((program_info.len() & 0x00FF) >> 0) as u8;
+    new_pmt.extend(program_info);
+
+    let (mut video_pid, mut audio_pid) = (None, None);
+    program.dropped_pids.clear();
+    // (source PID, stream_type, component_tag) of the metadata streams, each one taken by one input stream at most
+    let mut components = program.meta.replace_pmt_streams().iter().map(|(source_pid, (stream_type, es_info))| (*source_pid, *stream_type, mpeg2ts::pmt::component_tag(es_info))).collect::<Vec<_>>();
+    components.sort();
+    let mut existing_sources = HashSet::new();
+    let (mut videos, mut audios) = (0, 0);
+    while begin < mpeg2ts::section::BASIC_HEADER_SIZE + mpeg2ts::section::section_length(pmt) - mpeg2ts::section::CRC_SIZE {
+      let stream_type = pmt[begin + 0];
+      let elementary_pid = (((pmt[begin + 1] & 0x1F) as u16) << 8) | ((pmt[begin + 2] as u16) << 0);
+      let es_info_length = (((pmt[begin + 3] & 0x0F) as usize) << 8) | ((pmt[begin + 4] as usize) << 0);
+      if video_pid.is_none() && mpeg2ts::pmt::is_video_stream_type(stream_type) {
+        video_pid = Some(elementary_pid);
+      }
+      if audio_pid.is_none() && mpeg2ts::pmt::is_audio_stream_type(stream_type) {
+        audio_pid = Some(elementary_pid);
+      }
+
+      let es_info = &pmt[begin + 5 .. begin + 5 + es_info_length];
+      let component_tag = mpeg2ts::pmt::component_tag(es_info);
+      if let Some(source_pid) = existing_source(&components, &existing_sources, stream_type, es_info) {
+        if program.existing_pids.insert(elementary_pid) {
+          self.events.push_back(Event::ExistingStream { program_number: program.pmt_program_number, pid: elementary_pid, stream_type, component_tag, policy: self.existing_streams });
+        }
+        existing_sources.insert(source_pid);
+        if self.existing_streams == ExistingStreams::Replace {
+          program.dropped_pids.insert(elementary_pid);
+          self.input_pids.remove(&elementary_pid);
+          begin += 5 + es_info_length;
+          continue;
+        }
+      }
+
+      let injected_tag = match (self.component_tags, component_tag) {
+        (ComponentTags::None, _) | (_, Some(_)) => None,
+        (mode, None) => {
+          let is_video = mpeg2ts::pmt::is_video_stream_type(stream_type);
+          let is_audio = mpeg2ts::pmt::is_audio_stream_type(stream_type);
+          let order = if is_video { videos } else { audios };
+          let source = program.meta.components().iter()
+            .filter(|(source_type, _)| if is_video { mpeg2ts::pmt::is_video_stream_type(*source_type) } else { mpeg2ts::pmt::is_audio_stream_type(*source_type) })
+            .nth(order).and_then(|(_, tag)| *tag);
+          match (is_video || is_audio, mode, source) {
+            (false, _, _) => None,
+            (true, ComponentTags::Source, Some(tag)) => Some(tag),
+            (true, _, _) => Some(if is_video { 0x00 } else { 0x10 } + order as u8),
+          }
+        }
+      };
+      if mpeg2ts::pmt::is_video_stream_type(stream_type) { videos += 1; }
+      if mpeg2ts::pmt::is_audio_stream_type(stream_type) { audios += 1; }
+
+      match injected_tag {
+        Some(tag) => {
+          let es_info_length = es_info_length + 3;
+          new_pmt.extend(pmt[begin..begin + 3].iter());
+          new_pmt.push((pmt[begin + 3] & 0xF0) | ((es_info_length & 0x0F00) >> 8) as u8);
+          new_pmt.push(((es_info_length & 0x00FF) >> 0) as u8);
+          new_pmt.extend([0x52, 0x01, tag]);
+          new_pmt.extend(es_info);
+        },
+        None => new_pmt.extend(pmt[begin..begin + 5 + es_info_length].iter()),
+      }
+      self.input_pids.insert(elementary_pid);
+      begin += 5 + es_info_length;
+    }
+    self.programs[index].clock.set_video_pid(video_pid);
+    self.programs[index].clock.set_audio_pid(audio_pid);
+    let mut append_streams = Vec::new();
+    self.programs[index].skipped_pids.clear();
+    for (source_pid, (stream_type, esinfo)) in self.programs[index].meta.replace_pmt_streams().clone() {
+      if self.existing_streams == ExistingStreams::Keep && existing_sources.contains(&source_pid) {
+        self.programs[index].skipped_pids.insert(source_pid);
+        continue;
+      }
+      append_streams.push((stream_type, self.output_pid(index, source_pid), esinfo));
+    }
+    append_streams.sort_by(|(_, p1, _), (_, p2, _)| { p1.cmp(p2) });
+    for (stream_type, elementary_pid, esinfo) in &append_streams {
+      new_pmt.push(*stream_type);
+      new_pmt.push(((elementary_pid & 0x1F00) >> 8) as u8);
+      new_pmt.push(((elementary_pid & 0x00FF) >> 0) as u8);
+      new_pmt.push(((esinfo.len() & 0x0F00) >> 8) as u8);
+      new_pmt.push(((esinfo.len() & 0x00FF) >> 0) as u8);
+      new_pmt.extend(esinfo);
+    }
+
+    // section length
+    new_pmt[1] = (new_pmt[1] & 0xF0) | (((new_pmt.len() + mpeg2ts::section::CRC_SIZE - mpeg2ts::section::BASIC_HEADER_SIZE) & 0x0F00) >> 8) as u8;
+    new_pmt[2] = (((new_pmt.len() + mpeg2ts::section::CRC_SIZE - mpeg2ts::section::BASIC_HEADER_SIZE) & 0x00FF) >> 0) as u8;
+
+    // version number, starting from the input's and bumped whenever the output changes
+    let program = &mut self.programs[index];
+    new_pmt[5] &= 0xC1;
+    let version = match (program.pmt_version, &program.pmt) {
+      (Some(version), Some(previous)) if *previous == new_pmt => version,
+      (Some(version), _) => (version + 1) & 0x1F,
+      (None, _) => mpeg2ts::section::version_number(pmt),
+    };
+    program.pmt = Some(new_pmt.clone());
+    program.pmt_version = Some(version);
+    new_pmt[5] |= version << 1;
+
+    // crc32
+    let crc32 = mpeg2ts::section::crc32(&new_pmt);
+    new_pmt.push(((crc32 & 0xFF000000u32) >> 24) as u8);
+    new_pmt.push(((crc32 & 0x00FF0000u32) >> 16) as u8);
+    new_pmt.push(((crc32 & 0x0000FF00u32) >> 8) as u8);
+    new_pmt.push(((crc32 & 0x000000FFu32) >> 0) as u8);
+
+    for mut packet in packtize_section(&new_pmt, pid, 0) {
+      self.continuity_counters.stamp(&mut packet);
+      output.push(packet);
+    }
+    return Ok(());
+  }
+
+  fn align(&mut self, index: usize) -> Result<()> {
+    let program = &mut self.programs[index];
+
+    let (Some(input_first_pcr), Some(meta_first_pcr)) = (program.clock.first_pcr(), program.meta.clock().first_pcr()) else { return Ok(()); };
+    if !(program.clock.pts_lead().is_some() || program.meta.clock().pts_lead().is_none() || program.clock.elapsed() >= metadata::ALIGNMENT_LIMIT) { return Ok(()); }
+
+    let lead_difference = match (program.clock.pts_lead(), program.meta.clock().pts_lead()) {
+      (Some(input_lead), Some(meta_lead)) => Some(input_lead - meta_lead),
+      _ => None,
+    };
+    let position = mpeg2ts::packet::timestamp_difference(input_first_pcr, meta_first_pcr) + lead_difference.unwrap_or(0);
+    self.events.push_back(Event::Aligned { program_number: program.pmt_program_number, position, lead_difference });
+
+    // metadata before the input start belongs to the part which was cut away
+    if position > 0 {
+      while program.meta.pop_before(position as u64)?.is_some() {}
+    }
+    program.start_position = Some(position);
+    return Ok(());
+  }
+
+  fn flush_metadata(&mut self, index: usize, output: &mut Vec<Packet>) -> Result<()> {
+    let threshold = {
+      let program = &self.programs[index];
+      program.start_position.map(|position| position + program.clock.position() as i64).unwrap_or(0).max(0) as u64
+    };
+    loop {
+      let queued = self.programs[index].meta.pop_before(threshold)?;
+      if self.programs[index].meta.take_pmt_changed() {
+        self.update_pmt(index, output)?;
+      }
+      let Some(mut queued) = queued else { break; };
+
+      if self.si_policies.policy(queued.pid) == SiPolicy::Merge {
+        for section in self.si_merger.push_metadata(&queued.packet) {
+          self.output_section(&section, queued.pid, output);
+        }
+        continue;
+      }
+
+      if self.programs[index].skipped_pids.contains(&queued.pid) {
+        self.programs[index].left_out_pids.insert(queued.pid);
+        continue;
+      }
+
+      let mut stream_type = None;
+      if let Some((replaced_stream_type, _)) = self.programs[index].meta.replace_pmt_streams().get(&queued.pid) {
+        let replaced_stream_type = *replaced_stream_type;
+        let replaced_pid = self.output_pid(index, queued.pid);
+        queued.packet[1] = (queued.packet[1] & 0xE0) | (((replaced_pid & 0x1F00) >> 8) as u8);
+        queued.packet[2] = (replaced_pid & 0x00FF) as u8;
+        stream_type = Some(replaced_stream_type);
+      }
+
+      let program = &mut self.programs[index];
+      // metadata timestamp -> metadata timeline -> output (input) timeline -> input timestamp
+      match (&mut program.pes_retimer, stream_type, program.clock.base(), program.start_position) {
+        (Some(retimer), Some(0x06), Some(input_base), Some(position)) => {
+          output.extend(retimer.push(&queued.packet, queued.base + queued.shift - position - input_base, &mut self.continuity_counters));
+        },
+        _ => {
+          let skipped = queued.skipped || program.left_out_pids.remove(&queued.pid);
+          self.continuity_counters.forward(&queued.packet, skipped, output);
+        },
+      };
+    };
+    return Ok(());
+  }
+
+  // outputs the PMT again for a change of the metadata PMT, once the input PMT is known
+  fn update_pmt(&mut self, index: usize, output: &mut Vec<Packet>) -> Result<()> {
+    let (Some(pmt), Some(pid)) = (self.programs[index].input_pmt.clone(), self.programs[index].pmt_pid) else { return Ok(()); };
+    let version = self.programs[index].pmt_version;
+    self.rewrite_pmt(index, &pmt, pid, output)?;
+
+    let program = &self.programs[index];
+    if program.pmt_version != version {
+      let mut pids = program.meta.replace_pmt_streams().keys().filter(|source| !program.skipped_pids.contains(source)).filter_map(|source| program.output_pids.get(source).copied()).collect::<Vec<_>>();
+      pids.sort();
+      self.events.push_back(Event::StreamsChanged { program_number: program.pmt_program_number, pids });
+    }
+    return Ok(());
+  }
+
+  // output PID of a metadata stream, keeping the requested PID unless the input or another stream already uses it
+  fn output_pid(&mut self, index: usize, source: u16) -> u16 {
+    if let Some(pid) = self.programs[index].output_pids.get(&source) { return *pid; }
+
+    let requested = self.pid_map.get(source).unwrap_or(source);
+    let is_used = |pid: u16| {
+      pid < pid_map::MIN_ELEMENTARY_PID || self.input_pids.contains(&pid) || self.programs[0].meta.si_pids().contains(&pid) ||
+      self.programs.iter().any(|program| program.pmt_pid == Some(pid) || program.clock.pcr_pid() == Some(pid) || program.output_pids.values().any(|output_pid| *output_pid == pid))
+    };
+    let pid = if !is_used(requested) {
+      requested
+    } else {
+      let free = (requested + 1 ..= pid_map::MAX_ELEMENTARY_PID).chain(pid_map::MIN_ELEMENTARY_PID .. requested).find(|pid| !is_used(*pid));
+      let pid = free.unwrap_or(requested);
+      self.events.push_back(Event::PidRemapped { source, requested, pid });
+      pid
+    };
+
+    self.programs[index].output_pids.insert(source, pid);
+    return pid;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const AC3: [u8; 3] = [0x6A, 0x01, 0x00];
+  const DATA_COMPONENT: [u8; 4] = [0xFD, 0x02, 0x00, 0x0C];
+
+  fn psi(table_id: u8, table_id_extension: u16, body: &[u8]) -> mpeg2ts::section::Section {
+    let section_length = 5 + body.len() + mpeg2ts::section::CRC_SIZE;
+    let mut section = vec![table_id, 0xB0 | (section_length >> 8) as u8, section_length as u8, (table_id_extension >> 8) as u8, table_id_extension as u8, 0xC1, 0x00, 0x00];
+    section.extend(body);
+    let crc = mpeg2ts::section::crc32(&section);
+    section.extend(crc.to_be_bytes());
+    return section;
+  }
+
+  // PAT of one program, and its PMT of (stream_type, PID, ES info)
+  fn program(program_number: u16, pmt_pid: u16, pcr_pid: u16, streams: &[(u8, u16, &[u8])]) -> Vec<Packet> {
+    let pat = psi(0x00, 1, &[(program_number >> 8) as u8, program_number as u8, 0xE0 | (pmt_pid >> 8) as u8, pmt_pid as u8]);
+    let mut body = vec![0xE0 | (pcr_pid >> 8) as u8, pcr_pid as u8, 0xF0, 0x00];
+    for (stream_type, pid, es_info) in streams {
+      body.extend([*stream_type, 0xE0 | (pid >> 8) as u8, *pid as u8, 0xF0, es_info.len() as u8]);
+      body.extend(*es_info);
+    }
+    let mut packets = packtize_section(&pat, 0, 0);
+    packets.extend(packtize_section(&psi(0x02, program_number, &body), pmt_pid, 0));
+    return packets;
+  }
+
+  fn payload_packet(pid: u16, cc: u8) -> Packet {
+    let mut packet = [0xFFu8; mpeg2ts::packet::PACKET_SIZE];
+    packet[0..4].copy_from_slice(&[0x47, (pid >> 8) as u8, pid as u8, 0x10 | cc]);
+    return packet;
+  }
+
+  // elementary PIDs of the output PMT, and the PIDs of the other packets output
+  fn transplant(input_streams: &[(u8, u16, &[u8])]) -> (Vec<u16>, HashSet<u16>) {
+    let metadata = program(0x0400, 0x01F0, 0x01FF, &[(0x06, 0x0138, &[0x52, 0x01, 0x38])]).concat();
+    let meta = MetadataSource::new(std::io::Cursor::new(metadata), metadata::MetadataOptions::default());
+    let mut transplanter = Transplanter::new(meta, None, TransplantOptions::default()).unwrap();
+
+    let mut input = program(0x0001, 0x1000, 0x0100, input_streams);
+    for (_, pid, _) in input_streams {
+      input.push(payload_packet(*pid, 0));
+    }
+    let mut pmt_reader = SectionReader::new();
+    let mut pids = HashSet::new();
+    for packet in input {
+      for packet in transplanter.push(&packet).unwrap() {
+        match mpeg2ts::packet::pid(&packet) {
+          0x0000 => {},
+          0x1000 => pmt_reader.push(&packet),
+          pid => { pids.insert(pid); },
+        }
+      }
+    }
+
+    let pmt = pmt_reader.pop().unwrap();
+    let mut streams = Vec::new();
+    let mut begin = mpeg2ts::section::EXTENDED_HEADER_SIZE + 4;
+    while begin < pmt.len() - mpeg2ts::section::CRC_SIZE {
+      streams.push((((pmt[begin + 1] & 0x1F) as u16) << 8) | pmt[begin + 2] as u16);
+      begin += 5 + ((((pmt[begin + 3] & 0x0F) as usize) << 8) | pmt[begin + 4] as usize);
+    }
+    return (streams, pids);
+  }
+
+  #[test]
+  fn existing_source_by_stream_type() {
+    // captions 0x30 and data broadcasting 0x40 on 0x06, carousel 0x0D without a tag
+    let components = [(0x130, 0x06, Some(0x30)), (0x140, 0x06, Some(0x40)), (0x150, 0x0D, None)];
+    let mut taken = HashSet::new();
+
+    assert_eq!(existing_source(&components, &taken, 0x06, &[0x52, 0x01, 0x40]), Some(0x140));
+    assert_eq!(existing_source(&components, &taken, 0x06, &[0x52, 0x01, 0x38]), None);
+    assert_eq!(existing_source(&components, &taken, 0x0D, &[0x52, 0x01, 0x50]), Some(0x150));
+    assert_eq!(existing_source(&components, &taken, 0x1B, &[]), None);
+
+    // an untagged input stream takes the first metadata stream left, when it is ARIB data
+    assert_eq!(existing_source(&components, &taken, 0x06, &DATA_COMPONENT), Some(0x130));
+    taken.insert(0x130);
+    assert_eq!(existing_source(&components, &taken, 0x06, &DATA_COMPONENT), Some(0x140));
+    taken.insert(0x140);
+    assert_eq!(existing_source(&components, &taken, 0x06, &DATA_COMPONENT), None);
+  }
+
+  #[test]
+  fn other_private_data_is_not_existing() {
+    let components = [(0x130, 0x06, Some(0x30)), (0x150, 0x0D, None)];
+    let taken = HashSet::new();
+    // AC-3, DVB subtitles, ISO 639 language, registration, and nothing at all
+    for es_info in [&AC3[..], &[0x59, 0x08, b'j', b'p', b'n', 0x10, 0x00, 0x01, 0x00, 0x01], &[0x0A, 0x04, b'j', b'p', b'n', 0x00], &[0x05, 0x04, b'O', b'p', b'u', b's'], &[]] {
+      assert_eq!(existing_source(&components, &taken, 0x06, es_info), None);
+    }
+    // a tag outside of ARIB captions, superimpose and data broadcasting
+    assert_eq!(existing_source(&components, &taken, 0x06, &[0x52, 0x01, 0x10]), None);
+  }
+
+  #[test]
+  fn ac3_on_private_data_keeps_its_audio() {
+    let (streams, pids) = transplant(&[(0x02, 0x0100, &[]), (0x06, 0x0101, &AC3)]);
+    assert_eq!(streams, vec![0x0100, 0x0101, 0x0138]);
+    assert!(pids.contains(&0x0101));
+
+    // superimpose of the input is replaced by the metadata's
+    let (streams, pids) = transplant(&[(0x02, 0x0100, &[]), (0x06, 0x0102, &DATA_COMPONENT)]);
+    assert_eq!(streams, vec![0x0100, 0x0138]);
+    assert!(!pids.contains(&0x0102));
+  }
+}